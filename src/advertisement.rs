@@ -0,0 +1,131 @@
+//! Relay advertisement exchange.
+//!
+//! Launchers that know about it connect to the advertisement listener and send
+//! a short request; the proxy answers with its region and the RTT the prober
+//! currently measures towards the first listener's upstream, so the launcher
+//! can pick the closest relay.
+//!
+//! Request:  `[u16 length][b"RLAY"][u8 version]`
+//! Response: `[u16 length][b"RLAY"][u8 version][u16 region length][region][u32 rtt ms]`
+//!
+//! All integers are little endian, like the rest of the protocol. An RTT of
+//! `u32::MAX` means the upstream could not be reached.
+
+use crate::config::{AdvertisementConfig, Config};
+use crate::probe::ProbeResults;
+use crate::{debug, info};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+const MAGIC: &[u8; 4] = b"RLAY";
+const VERSION: u8 = 1;
+const REQUEST_LENGTH: usize = 2 + MAGIC.len() + 1;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+const RTT_UNREACHABLE: u32 = u32::MAX;
+/// Longest region whose response still fits the u16 length header.
+pub const MAX_REGION_LENGTH: usize = u16::MAX as usize - (MAGIC.len() + 1 + 2 + 4);
+
+pub async fn run(
+    config: AdvertisementConfig,
+    shared: watch::Receiver<Arc<Config>>,
+    probes: ProbeResults,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("Advertising region {} on {}", config.region, config.listen);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        // The region and target follow reloads; only the listen address is fixed.
+        let current = Arc::clone(&shared.borrow());
+        let region = current
+            .advertisement
+            .as_ref()
+            .map_or_else(|| config.region.clone(), |advertisement| advertisement.region.clone());
+        let target = advertised_target(&current);
+        let probes = probes.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &region, &target, &probes).await {
+                debug!("[advertisement] - Request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn answer(
+    mut stream: TcpStream,
+    region: &str,
+    target: &str,
+    probes: &ProbeResults,
+) -> io::Result<()> {
+    let mut request = [0u8; REQUEST_LENGTH];
+    timeout(REQUEST_TIMEOUT, stream.read_exact(&mut request))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request received"))??;
+
+    if !is_request(&request) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an advertisement request"));
+    }
+
+    let rtt_ms = upstream_rtt(target, probes);
+    stream.write_all(&encode_response(region, rtt_ms)).await?;
+    stream.shutdown().await
+}
+
+/// The upstream whose RTT is advertised: the first address the first
+/// listener forwards to.
+fn advertised_target(config: &Config) -> String {
+    let listener = config.listeners().remove(0);
+    crate::routing::resolve(config, &listener)
+        .ok()
+        .and_then(|destination| destination.addresses.first().cloned())
+        .unwrap_or_default()
+}
+
+fn is_request(bytes: &[u8]) -> bool {
+    bytes.len() == REQUEST_LENGTH
+        && u16::from_le_bytes([bytes[0], bytes[1]]) as usize == REQUEST_LENGTH - 2
+        && &bytes[2..6] == MAGIC
+        && bytes[6] == VERSION
+}
+
+fn encode_response(region: &str, rtt_ms: u32) -> Vec<u8> {
+    let region = &region.as_bytes()[..region.len().min(MAX_REGION_LENGTH)];
+    let body_length = MAGIC.len() + 1 + 2 + region.len() + 4;
+
+    let mut response = Vec::with_capacity(2 + body_length);
+    response.extend_from_slice(&(body_length as u16).to_le_bytes());
+    response.extend_from_slice(MAGIC);
+    response.push(VERSION);
+    response.extend_from_slice(&(region.len() as u16).to_le_bytes());
+    response.extend_from_slice(region);
+    response.extend_from_slice(&rtt_ms.to_le_bytes());
+    response
+}
+
+/// Returns the averaged upstream RTT in milliseconds as last measured by the
+/// prober, or `RTT_UNREACHABLE` when no probe has succeeded recently.
+fn upstream_rtt(target: &str, probes: &ProbeResults) -> u32 {
+    probes
+        .health(target)
+        .and_then(|health| health.rtt)
+        .map(|rtt| rtt.as_millis().min(RTT_UNREACHABLE as u128 - 1) as u32)
+        .unwrap_or(RTT_UNREACHABLE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_region_fits_the_length_header() {
+        let region = "r".repeat(MAX_REGION_LENGTH + 10);
+        let response = encode_response(&region, 5);
+        assert_eq!(response.len(), 2 + u16::MAX as usize);
+        assert_eq!(u16::from_le_bytes([response[0], response[1]]), u16::MAX);
+    }
+}
@@ -0,0 +1,73 @@
+//! Startup summary, so operators can confirm at a glance how a node is set up.
+
+use crate::config::{Config, ListenerConfig};
+use crate::{features, info, routing};
+
+pub fn log(config: &Config) {
+    let features = features::enabled();
+    info!(
+        "proxi {} (features: {})",
+        env!("CARGO_PKG_VERSION"),
+        if features.is_empty() { "none".to_string() } else { features.join(", ") }
+    );
+
+    for route in config.listeners() {
+        if let Ok(destination) = routing::resolve(config, &route) {
+            info!("[{}] Listening on {}, forwarding to {}", route.name(), route.listen, destination);
+        }
+        info!("[{}]   pipeline: {}", route.name(), pipeline(config, &route).join(" > "));
+        info!("[{}]   limits: {}", route.name(), limits(config, &route).join(", "));
+    }
+}
+
+/// Stages a client connection goes through, in order.
+fn pipeline(config: &Config, route: &ListenerConfig) -> Vec<String> {
+    let mut stages = Vec::new();
+    let knocking = route.knocking(config);
+    if knocking.enabled() {
+        stages.push(format!("knocking ({} ports)", knocking.ports.len()));
+    }
+    let compression = route.compression(config);
+    if compression.enabled {
+        stages.push(format!("zstd (level {}, optional)", compression.level));
+    }
+    if route.obfuscation(config).enabled {
+        stages.push("obfuscation".to_string());
+    }
+    let integrity = route.integrity(config);
+    if integrity.enabled {
+        stages.push(format!("hmac ({} key(s))", 1 + integrity.previous_keys.len()));
+    }
+    if route.waiting_room(config).max_sessions > 0 {
+        stages.push("waiting room".to_string());
+    }
+    if route.pacing(config).max_connects_per_sec > 0 {
+        stages.push("pacing".to_string());
+    }
+    if config.inspection.enabled && config.inspection.sequence_numbers {
+        stages.push("sequence numbers".to_string());
+    }
+    stages.push(if config.inspection.enabled { "inspection" } else { "passthrough" }.to_string());
+    stages.push("upstream".to_string());
+    stages
+}
+
+fn limits(config: &Config, route: &ListenerConfig) -> Vec<String> {
+    let mut limits = Vec::new();
+    let waiting_room = route.waiting_room(config);
+    if waiting_room.max_sessions > 0 {
+        limits.push(format!(
+            "{} sessions (queue {}, wait {}s)",
+            waiting_room.max_sessions, waiting_room.queue_size, waiting_room.max_wait_secs
+        ));
+    }
+    let pacing = route.pacing(config);
+    if pacing.max_connects_per_sec > 0 {
+        limits.push(format!(
+            "{} connects/s (queue {}, wait {}s)",
+            pacing.max_connects_per_sec, pacing.queue_size, pacing.max_wait_secs
+        ));
+    }
+    limits.push(format!("messages up to {} bytes", config.buffers.message_max_size));
+    limits
+}
@@ -0,0 +1,40 @@
+//! Free list of `NetworkMessage` buffers.
+//!
+//! Built messages take their buffer from here and hand it back when they are
+//! dropped, so a steady stream of packets reuses a few allocations instead of
+//! allocating a fresh buffer for every one. Buffers are recycled without being
+//! zeroed; a message only ever reads bytes it has written.
+
+use bytes::BytesMut;
+use std::sync::Mutex;
+
+/// Buffers kept around at most. Anything beyond that is freed, so a burst
+/// doesn't pin its peak memory for the lifetime of the process.
+const MAX_FREE_BUFFERS: usize = 64;
+
+static FREE: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+/// An empty buffer with room for at least `capacity` bytes.
+pub fn take(capacity: usize) -> BytesMut {
+    let recycled = FREE.lock().unwrap().pop();
+    match recycled {
+        Some(mut buffer) => {
+            buffer.clear();
+            buffer.reserve(capacity);
+            buffer
+        }
+        None => BytesMut::with_capacity(capacity),
+    }
+}
+
+/// Returns a buffer for later messages to reuse.
+pub fn give_back(buffer: BytesMut) {
+    if buffer.capacity() == 0 {
+        return;
+    }
+    let mut free = FREE.lock().unwrap();
+    if free.len() < MAX_FREE_BUFFERS {
+        free.push(buffer);
+    }
+}
+
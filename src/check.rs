@@ -0,0 +1,89 @@
+//! `--check`: validates the configuration without starting the proxy.
+
+use crate::config::Config;
+use crate::{info, routing};
+use std::net::SocketAddr;
+use tokio::net::lookup_host;
+
+/// Checks every address in `config`, printing each problem found.
+/// Returns whether the configuration is usable.
+pub async fn run(config: &Config) -> bool {
+    let mut problems = Vec::new();
+    let listeners = config.listeners();
+
+    let mut bound: Vec<(SocketAddr, String)> = Vec::new();
+    for listener in &listeners {
+        let ports = &listener.knocking(config).ports;
+        if ports.len() > 1 && ports.windows(2).all(|pair| pair[0] < pair[1]) {
+            eprintln!(
+                "Warning: listener {} knocks in ascending port order, which a port scan completes by accident",
+                listener.name()
+            );
+        }
+        let knocks = listener.knock_addresses(config);
+        let addresses = std::iter::once(("listen", &listener.listen)).chain(knocks.iter().map(|knock| ("knock", knock)));
+        for (kind, listen) in addresses {
+            match resolve(listen).await {
+                Ok(addresses) => {
+                    for address in addresses {
+                        if let Some((_, other)) = bound.iter().find(|(bound, _)| *bound == address) {
+                            problems.push(format!(
+                                "listener {} binds {} which is already used by {}",
+                                listener.name(),
+                                address,
+                                other
+                            ));
+                        }
+                        bound.push((address, listener.name().to_string()));
+                    }
+                }
+                Err(e) => problems.push(format!("listener {}: {} address {}", listener.name(), kind, e)),
+            }
+        }
+    }
+
+    if let Some(advertisement) = &config.advertisement {
+        match resolve(&advertisement.listen).await {
+            Ok(addresses) => {
+                for address in addresses {
+                    if let Some((_, other)) = bound.iter().find(|(bound, _)| *bound == address) {
+                        problems.push(format!("advertisement listener {} is already used by {}", address, other));
+                    }
+                }
+            }
+            Err(e) => problems.push(format!("advertisement listen address {}", e)),
+        }
+    }
+
+    for address in routing::all_addresses(config) {
+        match resolve(&address).await {
+            Ok(addresses) if addresses.iter().any(|address| address.port() == 0) => {
+                problems.push(format!("upstream address {} has port 0", address))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("upstream address {}", e)),
+        }
+    }
+
+    for problem in &problems {
+        eprintln!("Error: {}", problem);
+    }
+    if problems.is_empty() {
+        info!("Configuration OK: {} listener(s)", listeners.len());
+    }
+    problems.is_empty()
+}
+
+pub async fn resolve(address: &str) -> Result<Vec<SocketAddr>, String> {
+    match lookup_host(address).await {
+        Ok(addresses) => {
+            let addresses: Vec<SocketAddr> = addresses.collect();
+            if addresses.is_empty() {
+                Err(format!("{} did not resolve to any address", address))
+            } else {
+                Ok(addresses)
+            }
+        }
+        Err(e) => Err(format!("{} can't be resolved: {}", address, e)),
+    }
+}
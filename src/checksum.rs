@@ -0,0 +1,47 @@
+//! Adler-32, the checksum the game protocol sends in front of each packet body.
+
+const MODULUS: u32 = 65521;
+/// Bytes that can be summed before the 32-bit sums could overflow and have to
+/// be reduced, as in zlib.
+const BLOCK: usize = 5552;
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for block in data.chunks(BLOCK) {
+        for &byte in block {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adler-32 reduced after every byte, without the block shortcut.
+    fn reference(data: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in data {
+            a = (a + u32::from(byte)) % MODULUS;
+            b = (b + a) % MODULUS;
+        }
+        (b << 16) | a
+    }
+
+    #[test]
+    fn known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"hello"), 0x062c_0215);
+    }
+
+    #[test]
+    fn inputs_longer_than_a_block_are_reduced() {
+        let data = vec![0xff; 3 * BLOCK + 17];
+        assert_eq!(adler32(&data), reference(&data));
+    }
+}
@@ -0,0 +1,111 @@
+use clap::{ArgAction, Args, Parser, Subcommand};
+use crate::logging::LogLevel;
+use std::path::PathBuf;
+
+/// Command line options for the proxy.
+///
+/// Options given here take precedence over `PROXY_*` environment variables
+/// and the configuration file.
+#[derive(Parser, Debug, Clone)]
+#[command(name = "proxi", version, about = "TCP proxy that inspects NetworkMessage traffic")]
+pub struct Cli {
+    /// Path to a TOML configuration file [env: PROXY_CONFIG]
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Profile from the configuration file to apply [env: PROXY_PROFILE]
+    #[arg(short, long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// Address the proxy listens on for client connections [env: PROXY_LISTEN] [default: 127.0.0.1:7172]
+    #[arg(short, long, value_name = "ADDR")]
+    pub listen: Option<String>,
+
+    /// Address of the server the traffic is forwarded to [env: PROXY_TARGET] [default: 127.0.0.1:7173]
+    #[arg(short, long, value_name = "ADDR")]
+    pub target: Option<String>,
+
+    /// Increase output verbosity (-v connections and decoded fields, -vv packet dumps)
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Validate the configuration and exit, with a non-zero status on errors
+    #[arg(long)]
+    pub check: bool,
+
+    /// Print the effective configuration and resolved addresses, then exit
+    #[arg(long, conflicts_with = "check")]
+    pub dry_run: bool,
+
+    /// List the optional features compiled into this binary and exit
+    #[arg(long)]
+    pub features: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run on the player's machine as the near end of an obfuscated tunnel
+    #[cfg(feature = "tunnel")]
+    Client(ClientArgs),
+    /// Write a commented configuration file with every option and its default
+    GenerateConfig(GenerateConfigArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct GenerateConfigArgs {
+    /// File to write instead of printing to stdout
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Overwrite the output file if it already exists
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[cfg(feature = "tunnel")]
+#[derive(Args, Debug, Clone)]
+pub struct ClientArgs {
+    /// Path to a client TOML file with `local`, `remote`, `key`, `hmac_key` and `status_line`
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Local port or address the game client connects to [default: 7172]
+    #[arg(long, value_name = "PORT|ADDR")]
+    pub local: Option<String>,
+
+    /// Relay address to tunnel to
+    #[arg(long, value_name = "ADDR")]
+    pub remote: Option<String>,
+
+    /// Obfuscation key shared with the relay, 32 bytes hex encoded [env: PROXY_OBFUSCATION_KEY]
+    #[arg(long, value_name = "HEX")]
+    pub key: Option<String>,
+
+    /// HMAC key shared with the relay, at least 16 bytes hex encoded [env: PROXY_HMAC_KEY]
+    #[arg(long, value_name = "HEX")]
+    pub hmac_key: Option<String>,
+
+    /// Print a machine readable status line on stdout whenever the state changes
+    #[arg(long)]
+    pub status_line: bool,
+}
+
+impl Cli {
+    /// Log level requested with -v/-q, if any.
+    pub fn log_level(&self) -> Option<LogLevel> {
+        if self.quiet {
+            Some(LogLevel::Error)
+        } else if self.verbose > 0 {
+            Some(LogLevel::Info.raised_by(self.verbose))
+        } else {
+            None
+        }
+    }
+}
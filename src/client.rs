@@ -0,0 +1,123 @@
+//! `proxi client`: the near end of the tunnel, run on the player's machine.
+//!
+//! The game client connects to the local port as if it were the server; each
+//! connection is forwarded to the relay, obfuscated when a key is given and
+//! authenticated frame by frame when an HMAC key is given.
+//!
+//! With `status_line` set, a launcher can follow the tunnel by reading stdout,
+//! where every state change prints one line of `key=value` pairs:
+//!
+//! `status state=connected local=127.0.0.1:7172 remote=relay:443 obfuscated=true sessions=1`
+//!
+//! `state` is one of `listening`, `connected`, `disconnected` or `unreachable`.
+
+use crate::config::ClientConfig;
+use crate::debug;
+use crate::integrity;
+use crate::obfuscation::{self, KEY_LENGTH};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+
+struct Status {
+    enabled: bool,
+    local: String,
+    remote: String,
+    obfuscated: bool,
+    sessions: AtomicUsize,
+}
+
+impl Status {
+    fn report(&self, state: &str) {
+        if !self.enabled {
+            return;
+        }
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(
+            stdout,
+            "status state={} local={} remote={} obfuscated={} sessions={}",
+            state,
+            self.local,
+            self.remote,
+            self.obfuscated,
+            self.sessions.load(Ordering::SeqCst)
+        );
+        let _ = stdout.flush();
+    }
+}
+
+pub async fn run(config: ClientConfig) -> io::Result<()> {
+    let key = match &config.key {
+        Some(key) => Some(obfuscation::parse_key(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?),
+        None => None,
+    };
+    let hmac_key = match &config.hmac_key {
+        Some(key) => Some(integrity::parse_key(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?),
+        None => None,
+    };
+    let hmac_key = Arc::new(hmac_key);
+
+    let local = local_address(&config.local);
+    let listener = TcpListener::bind(&local).await?;
+
+    let status = Arc::new(Status {
+        enabled: config.status_line,
+        local,
+        remote: config.remote,
+        obfuscated: key.is_some(),
+        sessions: AtomicUsize::new(0),
+    });
+    status.report("listening");
+
+    loop {
+        let (inbound, peer) = listener.accept().await?;
+        let status = Arc::clone(&status);
+        let hmac_key = Arc::clone(&hmac_key);
+        debug!("Accepted connection from {}", peer);
+
+        tokio::spawn(async move {
+            if let Err(e) = forward(inbound, &status, key.as_ref(), hmac_key.as_deref()).await {
+                eprintln!("Error: connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// A bare port means a port on the loopback interface.
+fn local_address(local: &str) -> String {
+    if local.parse::<u16>().is_ok() {
+        format!("127.0.0.1:{}", local)
+    } else {
+        local.to_string()
+    }
+}
+
+async fn forward(
+    mut inbound: TcpStream,
+    status: &Status,
+    key: Option<&[u8; KEY_LENGTH]>,
+    hmac_key: Option<&[u8]>,
+) -> io::Result<()> {
+    let outbound = match TcpStream::connect(&status.remote).await {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            status.report("unreachable");
+            return Err(e);
+        }
+    };
+    let outbound = match key {
+        Some(key) => obfuscation::Stream::connect(outbound, key).await?,
+        None => obfuscation::Stream::plain(outbound),
+    };
+    let mut outbound = integrity::Stream::connect(outbound, hmac_key).await?;
+
+    status.sessions.fetch_add(1, Ordering::SeqCst);
+    status.report("connected");
+    let result = io::copy_bidirectional(&mut inbound, &mut outbound).await;
+    status.sessions.fetch_sub(1, Ordering::SeqCst);
+    status.report("disconnected");
+
+    result.map(|_| ())
+}
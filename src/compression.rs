@@ -0,0 +1,188 @@
+//! Optional zstd compression on the client leg.
+//!
+//! Patched clients and launchers open the connection with a hello packet:
+//!
+//! Hello: `[u16 length][b"ZSTD"][u8 version]`
+//!
+//! The proxy answers with the same packet and from then on every chunk in
+//! either direction between client and proxy is a `[u32 length][zstd frame]`.
+//! Vanilla clients never send the hello, so their connection stays plain;
+//! the upstream leg is never compressed.
+
+use bytes::{Bytes, BytesMut};
+use tokio::io;
+use tokio_util::codec::{BytesCodec, Decoder, Encoder};
+
+#[cfg(not(feature = "compression"))]
+use crate::config::CompressionConfig;
+#[cfg(not(feature = "compression"))]
+use tokio::net::TcpStream;
+
+#[cfg(feature = "compression")]
+pub use zstd_codec::{negotiate, ZstdCodec};
+
+/// Without the compression feature every client stays plain; the config
+/// loader already refused `compression.enabled`.
+#[cfg(not(feature = "compression"))]
+pub async fn negotiate(_inbound: &mut TcpStream, _config: &CompressionConfig) -> io::Result<ClientCodec> {
+    Ok(ClientCodec::Plain(BytesCodec::new()))
+}
+
+/// Codec used for the client leg once negotiation is done.
+pub enum ClientCodec {
+    Plain(BytesCodec),
+    #[cfg(feature = "compression")]
+    Zstd(ZstdCodec),
+}
+
+impl ClientCodec {
+    pub fn is_compressed(&self) -> bool {
+        !matches!(self, ClientCodec::Plain(_))
+    }
+
+    /// A second codec of the same kind, for the other half of the connection.
+    pub fn split(&self) -> ClientCodec {
+        match self {
+            ClientCodec::Plain(_) => ClientCodec::Plain(BytesCodec::new()),
+            #[cfg(feature = "compression")]
+            ClientCodec::Zstd(codec) => ClientCodec::Zstd(ZstdCodec { level: codec.level }),
+        }
+    }
+}
+
+impl Decoder for ClientCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        match self {
+            ClientCodec::Plain(codec) => codec.decode(src),
+            #[cfg(feature = "compression")]
+            ClientCodec::Zstd(codec) => codec.decode(src),
+        }
+    }
+}
+
+impl Encoder<Bytes> for ClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+        match self {
+            ClientCodec::Plain(codec) => codec.encode(item, dst),
+            #[cfg(feature = "compression")]
+            ClientCodec::Zstd(codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+mod zstd_codec {
+    use super::ClientCodec;
+    use crate::config::CompressionConfig;
+    use bytes::{Buf, BufMut, Bytes, BytesMut};
+    use std::time::Duration;
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio::time::Instant;
+    use tokio_util::codec::{BytesCodec, Decoder, Encoder};
+
+    const MAGIC: &[u8; 4] = b"ZSTD";
+    const VERSION: u8 = 1;
+    const HELLO_LENGTH: usize = 2 + MAGIC.len() + 1;
+    const FRAME_HEADER_LENGTH: usize = 4;
+    /// Upper bound for a frame, compressed or not, so a peer can't make us buffer
+    /// or inflate unbounded amounts of data.
+    const MAX_FRAME_LENGTH: usize = 1 << 20;
+    const PEEK_INTERVAL: Duration = Duration::from_millis(5);
+
+    fn hello() -> [u8; HELLO_LENGTH] {
+        let mut hello = [0u8; HELLO_LENGTH];
+        hello[..2].copy_from_slice(&((HELLO_LENGTH - 2) as u16).to_le_bytes());
+        hello[2..6].copy_from_slice(MAGIC);
+        hello[6] = VERSION;
+        hello
+    }
+
+    /// Waits up to `negotiation_timeout_ms` for the client's hello without
+    /// consuming anything else, and answers it when it arrives.
+    pub async fn negotiate(inbound: &mut TcpStream, config: &CompressionConfig) -> io::Result<ClientCodec> {
+        if !config.enabled {
+            return Ok(ClientCodec::Plain(BytesCodec::new()));
+        }
+
+        let expected = hello();
+        let deadline = Instant::now() + Duration::from_millis(config.negotiation_timeout_ms);
+        let mut peeked = [0u8; HELLO_LENGTH];
+
+        loop {
+            let available = tokio::select! {
+                result = inbound.peek(&mut peeked) => result?,
+                _ = tokio::time::sleep_until(deadline) => break,
+            };
+
+            // Nothing to negotiate with a client that already left or isn't sending a hello.
+            if available == 0 || peeked[..available] != expected[..available] {
+                break;
+            }
+            if available == HELLO_LENGTH {
+                inbound.read_exact(&mut peeked).await?;
+                inbound.write_all(&expected).await?;
+                return Ok(ClientCodec::Zstd(ZstdCodec { level: config.level }));
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+            // peek returns straight away while the hello is still incomplete
+            tokio::time::sleep(PEEK_INTERVAL).await;
+        }
+
+        Ok(ClientCodec::Plain(BytesCodec::new()))
+    }
+
+    pub struct ZstdCodec {
+        pub(super) level: i32,
+    }
+
+    impl Decoder for ZstdCodec {
+        type Item = BytesMut;
+        type Error = io::Error;
+
+        fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+            if src.len() < FRAME_HEADER_LENGTH {
+                return Ok(None);
+            }
+
+            let length = u32::from_le_bytes([src[0], src[1], src[2], src[3]]) as usize;
+            if length > MAX_FRAME_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("compressed frame of {} bytes exceeds {}", length, MAX_FRAME_LENGTH),
+                ));
+            }
+            if src.len() < FRAME_HEADER_LENGTH + length {
+                src.reserve(FRAME_HEADER_LENGTH + length - src.len());
+                return Ok(None);
+            }
+
+            src.advance(FRAME_HEADER_LENGTH);
+            let frame = src.split_to(length);
+            let decompressed = zstd::bulk::decompress(&frame, MAX_FRAME_LENGTH)?;
+            Ok(Some(BytesMut::from(&decompressed[..])))
+        }
+    }
+
+    impl Encoder<Bytes> for ZstdCodec {
+        type Error = io::Error;
+
+        fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> io::Result<()> {
+            let compressed = zstd::bulk::compress(&item, self.level)?;
+            if compressed.len() > MAX_FRAME_LENGTH {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk too large to compress"));
+            }
+            dst.reserve(FRAME_HEADER_LENGTH + compressed.len());
+            dst.put_u32_le(compressed.len() as u32);
+            dst.put_slice(&compressed);
+            Ok(())
+        }
+    }
+}
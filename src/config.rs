@@ -0,0 +1,1029 @@
+use crate::cli::Cli;
+#[cfg(feature = "tunnel")]
+use crate::cli::ClientArgs;
+use crate::logging::LogLevel;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:7172";
+pub const DEFAULT_TARGET_ADDRESS: &str = "127.0.0.1:7173";
+#[cfg(feature = "tunnel")]
+pub const DEFAULT_CLIENT_LOCAL: &str = "7172";
+pub const DEFAULT_MESSAGE_MAX_SIZE: usize = 65500;
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 32;
+pub const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+pub const DEFAULT_PACING_QUEUE_SIZE: usize = 1000;
+pub const DEFAULT_PACING_MAX_WAIT_SECS: u64 = 60;
+pub const DEFAULT_WAITING_ROOM_QUEUE_SIZE: usize = 500;
+pub const DEFAULT_WAITING_ROOM_MAX_WAIT_SECS: u64 = 600;
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+pub const DEFAULT_COMPRESSION_NEGOTIATION_TIMEOUT_MS: u64 = 200;
+pub const DEFAULT_RESOLVER_CACHE_SECS: u64 = 30;
+pub const DEFAULT_KNOCKING_WINDOW_SECS: u64 = 10;
+pub const DEFAULT_KNOCKING_OPEN_SECS: u64 = 60;
+pub const DEFAULT_PROBE_INTERVAL_SECS: u64 = 10;
+pub const DEFAULT_PROBE_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_PROBE_WINDOW: usize = 10;
+pub const DEFAULT_DESCRIPTORS_PAUSE_MS: u64 = 1000;
+pub const DEFAULT_DESCRIPTORS_IDLE_SECS: u64 = 300;
+
+pub const ENV_CONFIG: &str = "PROXY_CONFIG";
+pub const ENV_PROFILE: &str = "PROXY_PROFILE";
+pub const ENV_LISTEN: &str = "PROXY_LISTEN";
+pub const ENV_TARGET: &str = "PROXY_TARGET";
+pub const ENV_MESSAGE_MAX_SIZE: &str = "PROXY_MESSAGE_MAX_SIZE";
+pub const ENV_CHANNEL_CAPACITY: &str = "PROXY_CHANNEL_CAPACITY";
+pub const ENV_READ_BUFFER_SIZE: &str = "PROXY_READ_BUFFER_SIZE";
+pub const ENV_LOG_LEVEL: &str = "PROXY_LOG_LEVEL";
+#[cfg(feature = "tunnel")]
+pub const ENV_OBFUSCATION_KEY: &str = "PROXY_OBFUSCATION_KEY";
+#[cfg(feature = "tunnel")]
+pub const ENV_HMAC_KEY: &str = "PROXY_HMAC_KEY";
+
+/// Proxy settings loaded from a TOML file.
+///
+/// Every field is optional in the file; missing values fall back to the
+/// defaults below. `PROXY_*` environment variables override the file and
+/// command line options override both.
+///
+/// A file may also define `[profiles.<name>]` tables. Selecting one with
+/// `--profile` merges it over the rest of the file before it is read, so a
+/// profile only lists what differs, e.g. `[profiles.debug.logging]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the proxy listens on for client connections. Ignored when
+    /// `[[listeners]]` are configured.
+    pub listen: String,
+    /// Address of the server the traffic is forwarded to. Ignored when
+    /// `[[listeners]]` are configured.
+    pub target: String,
+    /// Listeners served by this process, each with its own destination. A
+    /// listener can override the pacing, waiting_room, compression,
+    /// obfuscation, integrity and knocking sections with its own
+    /// `[listeners.<section>]` table; keys left out of it take their
+    /// defaults, not the global values.
+    pub listeners: Vec<ListenerConfig>,
+    /// Named groups of upstream servers listeners can forward to.
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    pub resolver: ResolverConfig,
+    pub buffers: BufferConfig,
+    pub logging: LoggingConfig,
+    pub inspection: InspectionConfig,
+    pub pacing: PacingConfig,
+    pub waiting_room: WaitingRoomConfig,
+    pub compression: CompressionConfig,
+    pub obfuscation: ObfuscationConfig,
+    pub integrity: IntegrityConfig,
+    pub knocking: KnockingConfig,
+    pub probe: ProbeConfig,
+    /// What listeners do when the process runs out of file descriptors: an
+    /// alert is logged, a reserved descriptor is used to turn away one
+    /// waiting client and accepting pauses.
+    pub descriptors: DescriptorsConfig,
+    /// Relay advertisement listener, disabled unless configured.
+    pub advertisement: Option<AdvertisementConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Name used in logs, defaults to the listen address.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Address this listener accepts clients on.
+    pub listen: String,
+    /// Single server address to forward to.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Name of an `[upstreams.<name>]` group to forward to instead of `target`.
+    #[serde(default)]
+    pub upstream: Option<String>,
+    /// Overrides the global `[pacing]` section for this listener.
+    #[serde(default)]
+    pub pacing: Option<PacingConfig>,
+    /// Overrides the global `[waiting_room]` section for this listener.
+    #[serde(default)]
+    pub waiting_room: Option<WaitingRoomConfig>,
+    /// Overrides the global `[compression]` section for this listener.
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// Overrides the global `[obfuscation]` section for this listener.
+    #[serde(default)]
+    pub obfuscation: Option<ObfuscationConfig>,
+    /// Overrides the global `[integrity]` section for this listener.
+    #[serde(default)]
+    pub integrity: Option<IntegrityConfig>,
+    /// Overrides the global `[knocking]` section for this listener.
+    #[serde(default)]
+    pub knocking: Option<KnockingConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Servers of the group; clients are spread across them and the next one
+    /// is tried when a server can't be reached.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub addresses: Vec<String>,
+    /// Logical name resolved when clients connect, instead of `addresses`.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// How `name` is resolved: "dns" looks up `host:port` with the system
+    /// resolver, "static" in `[resolver.names]`, "srv" reads the SRV record
+    /// of a service name such as `_game._tcp.example.com` and tries its
+    /// targets by priority and weight.
+    #[serde(default)]
+    pub resolver: ResolverKind,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolverKind {
+    /// `host:port` looked up with the system resolver.
+    #[default]
+    Dns,
+    /// Looked up in `[resolver.names]`.
+    Static,
+    /// SRV record such as `_game._tcp.example.com`, ordered by priority and
+    /// weight and cached for the record's TTL.
+    Srv,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// How long resolved names are reused before being looked up again. SRV
+    /// records are reused for their own TTL instead.
+    pub cache_secs: u64,
+    /// Addresses of the names upstreams resolve with `resolver = "static"`.
+    pub names: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BufferConfig {
+    /// Size of a NetworkMessage buffer, header included. Larger client chunks
+    /// are forwarded without inspection.
+    pub message_max_size: usize,
+    /// Client chunks that may be queued towards the server before reading
+    /// from the client pauses.
+    pub channel_capacity: usize,
+    /// Initial read buffer size of each connection direction.
+    pub read_buffer_size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// One of "error", "info", "debug" or "trace".
+    pub level: LogLevel,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdvertisementConfig {
+    /// Address launchers connect to for the advertisement exchange.
+    pub listen: String,
+    /// Region name reported to launchers, e.g. "eu-west".
+    pub region: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InspectionConfig {
+    /// Decode client packets for the debug/trace output. Turning this off
+    /// forwards traffic untouched without parsing it.
+    pub enabled: bool,
+    /// Validate the sequence number newer clients send after the length
+    /// header and renumber packets from the proxy's own per-connection
+    /// counter. Needs `enabled`.
+    pub sequence_numbers: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PacingConfig {
+    /// New upstream connections allowed per second, 0 disables pacing.
+    pub max_connects_per_sec: u32,
+    /// Clients that may wait for a slot at once; further clients are dropped.
+    pub queue_size: usize,
+    /// Clients whose slot is further away than this are dropped.
+    pub max_wait_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WaitingRoomConfig {
+    /// Sessions allowed at once on a listener, 0 disables the cap.
+    pub max_sessions: usize,
+    /// Clients that may wait for a free session; further clients are dropped.
+    pub queue_size: usize,
+    /// Seconds between connect attempts while the upstream refuses clients,
+    /// 0 drops the client on the first refusal.
+    pub retry_secs: u64,
+    /// Clients still waiting after this long are dropped.
+    pub max_wait_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    /// Offer zstd compression to clients that open with a compression hello.
+    pub enabled: bool,
+    /// zstd compression level for data sent to the client.
+    pub level: i32,
+    /// How long a new client is given to send the hello before it is treated
+    /// as a vanilla client. Delays clients that wait for the server to speak
+    /// first by up to this much.
+    pub negotiation_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ObfuscationConfig {
+    /// Expect clients to connect through `proxi client` with the same key.
+    /// Can't be combined with compression on the same listener.
+    pub enabled: bool,
+    /// Shared key, 32 bytes hex encoded.
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntegrityConfig {
+    /// Expect clients to be paired proxies (`proxi client --hmac-key`) and
+    /// verify an HMAC on every frame they send. Can't be combined with
+    /// compression on the same listener.
+    pub enabled: bool,
+    /// Shared HMAC key, at least 16 bytes hex encoded.
+    pub key: String,
+    /// Keys still accepted from clients while they are moved over to `key`.
+    /// Sessions keep the key they connected with, so rotating keys with a
+    /// reload doesn't drop anyone.
+    pub previous_keys: Vec<String>,
+}
+
+impl IntegrityConfig {
+    /// The decoded keys accepted from clients, `key` first, or none when
+    /// integrity checking is off.
+    pub fn keys(&self) -> Result<Vec<Vec<u8>>, String> {
+        if !self.enabled {
+            return Ok(Vec::new());
+        }
+        #[cfg(feature = "tunnel")]
+        return std::iter::once(&self.key)
+            .chain(&self.previous_keys)
+            .map(|key| crate::integrity::parse_key(key))
+            .collect();
+        #[cfg(not(feature = "tunnel"))]
+        Err(crate::features::missing("tunnel", "[integrity]"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KnockingConfig {
+    /// Ports a client must connect to, in order, before the listener accepts
+    /// it. Empty disables knocking. They are bound on the listener's host
+    /// as ordinary TCP listeners and can't be changed without a restart.
+    /// Scanners see them open, and a scan of ascending ports knocks them in
+    /// order by accident, so don't list them in ascending order.
+    pub ports: Vec<u16>,
+    /// The whole sequence has to be knocked within this many seconds.
+    pub window_secs: u64,
+    /// How long a client that knocked may open new connections.
+    pub open_secs: u64,
+}
+
+impl KnockingConfig {
+    pub fn enabled(&self) -> bool {
+        !self.ports.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProbeConfig {
+    /// Probe upstreams even when nothing else asks for it. The advertisement
+    /// listener always enables probing.
+    pub enabled: bool,
+    /// Seconds between two probes of each upstream.
+    pub interval_secs: u64,
+    /// A connect slower than this counts as a lost probe.
+    pub timeout_ms: u64,
+    /// Number of recent probes the RTT average and loss ratio are taken over.
+    pub window: usize,
+}
+
+/// What listeners do when the process runs out of file descriptors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DescriptorsConfig {
+    /// How long accepting pauses before trying again.
+    pub pause_ms: u64,
+    /// Idle sessions closed, longest idle first, to free descriptors. 0 keeps
+    /// every session open.
+    pub shed_sessions: usize,
+    /// Only sessions without traffic for this many seconds are closed.
+    pub idle_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: DEFAULT_LISTEN_ADDRESS.to_string(),
+            target: DEFAULT_TARGET_ADDRESS.to_string(),
+            listeners: Vec::new(),
+            upstreams: BTreeMap::new(),
+            resolver: ResolverConfig::default(),
+            buffers: BufferConfig::default(),
+            logging: LoggingConfig::default(),
+            inspection: InspectionConfig::default(),
+            pacing: PacingConfig::default(),
+            waiting_room: WaitingRoomConfig::default(),
+            compression: CompressionConfig::default(),
+            obfuscation: ObfuscationConfig::default(),
+            integrity: IntegrityConfig::default(),
+            knocking: KnockingConfig::default(),
+            probe: ProbeConfig::default(),
+            descriptors: DescriptorsConfig::default(),
+            advertisement: None,
+        }
+    }
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            message_max_size: DEFAULT_MESSAGE_MAX_SIZE,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: LogLevel::Info,
+        }
+    }
+}
+
+impl Default for InspectionConfig {
+    fn default() -> Self {
+        InspectionConfig {
+            enabled: true,
+            sequence_numbers: false,
+        }
+    }
+}
+
+impl Default for PacingConfig {
+    fn default() -> Self {
+        PacingConfig {
+            max_connects_per_sec: 0,
+            queue_size: DEFAULT_PACING_QUEUE_SIZE,
+            max_wait_secs: DEFAULT_PACING_MAX_WAIT_SECS,
+        }
+    }
+}
+
+impl Default for WaitingRoomConfig {
+    fn default() -> Self {
+        WaitingRoomConfig {
+            max_sessions: 0,
+            queue_size: DEFAULT_WAITING_ROOM_QUEUE_SIZE,
+            retry_secs: 0,
+            max_wait_secs: DEFAULT_WAITING_ROOM_MAX_WAIT_SECS,
+        }
+    }
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: false,
+            level: DEFAULT_COMPRESSION_LEVEL,
+            negotiation_timeout_ms: DEFAULT_COMPRESSION_NEGOTIATION_TIMEOUT_MS,
+        }
+    }
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        ResolverConfig {
+            cache_secs: DEFAULT_RESOLVER_CACHE_SECS,
+            names: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for KnockingConfig {
+    fn default() -> Self {
+        KnockingConfig {
+            ports: Vec::new(),
+            window_secs: DEFAULT_KNOCKING_WINDOW_SECS,
+            open_secs: DEFAULT_KNOCKING_OPEN_SECS,
+        }
+    }
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        ProbeConfig {
+            enabled: false,
+            interval_secs: DEFAULT_PROBE_INTERVAL_SECS,
+            timeout_ms: DEFAULT_PROBE_TIMEOUT_MS,
+            window: DEFAULT_PROBE_WINDOW,
+        }
+    }
+}
+
+impl Default for DescriptorsConfig {
+    fn default() -> Self {
+        DescriptorsConfig {
+            pause_ms: DEFAULT_DESCRIPTORS_PAUSE_MS,
+            shed_sessions: 0,
+            idle_secs: DEFAULT_DESCRIPTORS_IDLE_SECS,
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.listen)
+    }
+
+    pub fn pacing<'a>(&'a self, config: &'a Config) -> &'a PacingConfig {
+        self.pacing.as_ref().unwrap_or(&config.pacing)
+    }
+
+    pub fn waiting_room<'a>(&'a self, config: &'a Config) -> &'a WaitingRoomConfig {
+        self.waiting_room.as_ref().unwrap_or(&config.waiting_room)
+    }
+
+    pub fn compression<'a>(&'a self, config: &'a Config) -> &'a CompressionConfig {
+        self.compression.as_ref().unwrap_or(&config.compression)
+    }
+
+    pub fn obfuscation<'a>(&'a self, config: &'a Config) -> &'a ObfuscationConfig {
+        self.obfuscation.as_ref().unwrap_or(&config.obfuscation)
+    }
+
+    pub fn integrity<'a>(&'a self, config: &'a Config) -> &'a IntegrityConfig {
+        self.integrity.as_ref().unwrap_or(&config.integrity)
+    }
+
+    pub fn knocking<'a>(&'a self, config: &'a Config) -> &'a KnockingConfig {
+        self.knocking.as_ref().unwrap_or(&config.knocking)
+    }
+
+    /// Addresses of the knock ports, on the same host as `listen`.
+    pub fn knock_addresses(&self, config: &Config) -> Vec<String> {
+        let host = self.listen.rsplit_once(':').map_or("", |(host, _)| host);
+        self.knocking(config)
+            .ports
+            .iter()
+            .map(|port| format!("{}:{}", host, port))
+            .collect()
+    }
+}
+
+impl Config {
+    /// Reads `path` and its includes, with the named profile merged in when
+    /// one is given. Validation waits for the overrides in `from_cli`.
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Config, ConfigError> {
+        let mut table = read_table(path, &mut Vec::new())?;
+
+        let profiles = table.remove("profiles");
+        if let Some(name) = profile {
+            let selected = profiles
+                .as_ref()
+                .and_then(|profiles| profiles.get(name))
+                .and_then(toml::Value::as_table)
+                .ok_or_else(|| ConfigError::UnknownProfile(path.to_path_buf(), name.to_string()))?;
+            merge_tables(&mut table, selected);
+        }
+
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// Builds the effective configuration: defaults, then the file given
+    /// with `--config` (or `PROXY_CONFIG`), then `PROXY_*` environment
+    /// variables, then the remaining command line options.
+    pub fn from_cli(cli: &Cli) -> Result<Config, ConfigError> {
+        let path = cli.config.clone().or_else(|| env_var(ENV_CONFIG).map(PathBuf::from));
+        let profile = cli.profile.clone().or_else(|| env_var(ENV_PROFILE));
+        let mut config = match (&path, &profile) {
+            (Some(path), _) => Config::load(path, profile.as_deref())?,
+            (None, Some(profile)) => {
+                return Err(ConfigError::Invalid(format!(
+                    "profile {} selected but no config file given",
+                    profile
+                )))
+            }
+            (None, None) => Config::default(),
+        };
+
+        let mut overrides_listener = config.apply_env()?;
+
+        if let Some(listen) = &cli.listen {
+            config.listen = listen.clone();
+            overrides_listener = true;
+        }
+        if let Some(target) = &cli.target {
+            config.target = target.clone();
+            overrides_listener = true;
+        }
+        if let Some(level) = cli.log_level() {
+            config.logging.level = level;
+        }
+
+        if overrides_listener && !config.listeners.is_empty() {
+            return Err(ConfigError::Invalid(
+                "--listen/--target and PROXY_LISTEN/PROXY_TARGET can't be combined with [[listeners]]".to_string(),
+            ));
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// The listeners to serve: the `[[listeners]]` entries, or a single one
+    /// built from the top-level `listen` and `target`.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerConfig {
+            name: None,
+            listen: self.listen.clone(),
+            target: Some(self.target.clone()),
+            upstream: None,
+            pacing: None,
+            waiting_room: None,
+            compression: None,
+            obfuscation: None,
+            integrity: None,
+            knocking: None,
+        }]
+    }
+
+    pub fn listener(&self, listen: &str) -> Option<ListenerConfig> {
+        self.listeners().into_iter().find(|listener| listener.listen == listen)
+    }
+
+    /// Applies `PROXY_*` overrides, returning whether the default listener
+    /// address or target was overridden.
+    fn apply_env(&mut self) -> Result<bool, ConfigError> {
+        let mut overrides_listener = false;
+        if let Some(listen) = env_var(ENV_LISTEN) {
+            self.listen = listen;
+            overrides_listener = true;
+        }
+        if let Some(target) = env_var(ENV_TARGET) {
+            self.target = target;
+            overrides_listener = true;
+        }
+        if let Some(size) = env_var(ENV_MESSAGE_MAX_SIZE) {
+            self.buffers.message_max_size = parse_size(ENV_MESSAGE_MAX_SIZE, &size)?;
+        }
+        if let Some(capacity) = env_var(ENV_CHANNEL_CAPACITY) {
+            self.buffers.channel_capacity = parse_size(ENV_CHANNEL_CAPACITY, &capacity)?;
+        }
+        if let Some(size) = env_var(ENV_READ_BUFFER_SIZE) {
+            self.buffers.read_buffer_size = parse_size(ENV_READ_BUFFER_SIZE, &size)?;
+        }
+        if let Some(level) = env_var(ENV_LOG_LEVEL) {
+            self.logging.level = level.parse().map_err(|_| {
+                ConfigError::Invalid(format!("{} is not a valid log level: {}", ENV_LOG_LEVEL, level))
+            })?;
+        }
+        Ok(overrides_listener)
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.buffers.message_max_size <= crate::BODY_OVERHEAD {
+            return Err(ConfigError::Invalid(format!(
+                "buffers.message_max_size must be greater than {}",
+                crate::BODY_OVERHEAD
+            )));
+        }
+        if self.buffers.channel_capacity == 0 {
+            return Err(ConfigError::Invalid("buffers.channel_capacity must be at least 1".to_string()));
+        }
+        if self.buffers.read_buffer_size == 0 {
+            return Err(ConfigError::Invalid("buffers.read_buffer_size must be at least 1".to_string()));
+        }
+
+        let listeners = self.listeners();
+        for (i, listener) in listeners.iter().enumerate() {
+            if listeners[..i].iter().any(|other| other.listen == listener.listen) {
+                return Err(ConfigError::Invalid(format!(
+                    "listen address {} is used by more than one listener",
+                    listener.listen
+                )));
+            }
+            crate::routing::resolve(self, listener).map_err(|e| ConfigError::Invalid(e.to_string()))?;
+
+            let obfuscation = listener.obfuscation(self);
+            if obfuscation.enabled {
+                #[cfg(feature = "tunnel")]
+                let key = crate::obfuscation::parse_key(&obfuscation.key).map(drop);
+                #[cfg(not(feature = "tunnel"))]
+                let key = Err(crate::features::missing("tunnel", "[obfuscation]"));
+                key.map_err(|e| ConfigError::Invalid(format!("listener {}: {}", listener.name(), e)))?;
+            }
+            let integrity = listener.integrity(self);
+            integrity
+                .keys()
+                .map_err(|e| ConfigError::Invalid(format!("listener {}: {}", listener.name(), e)))?;
+
+            let knocking = listener.knocking(self);
+            if knocking.ports.contains(&0) {
+                return Err(ConfigError::Invalid(format!("listener {}: knock port 0 is not allowed", listener.name())));
+            }
+            let knock_addresses = listener.knock_addresses(self);
+            for (j, address) in knock_addresses.iter().enumerate() {
+                if knock_addresses[..j].contains(address) {
+                    return Err(ConfigError::Invalid(format!(
+                        "listener {} knocks on {} more than once",
+                        listener.name(),
+                        address
+                    )));
+                }
+                if *address == listener.listen {
+                    return Err(ConfigError::Invalid(format!(
+                        "listener {} can't knock on its own port",
+                        listener.name()
+                    )));
+                }
+                if listeners[..i].iter().any(|other| other.knock_addresses(self).contains(address)) {
+                    return Err(ConfigError::Invalid(format!(
+                        "knock address {} is used by more than one listener, give each its own [listeners.knocking]",
+                        address
+                    )));
+                }
+            }
+
+            if listener.compression(self).enabled && !cfg!(feature = "compression") {
+                return Err(ConfigError::Invalid(format!(
+                    "listener {}: {}",
+                    listener.name(),
+                    crate::features::missing("compression", "[compression]")
+                )));
+            }
+            if listener.compression(self).enabled && (obfuscation.enabled || integrity.enabled) {
+                return Err(ConfigError::Invalid(format!(
+                    "listener {} can't combine compression with obfuscation or integrity",
+                    listener.name()
+                )));
+            }
+        }
+        if let Some(advertisement) = &self.advertisement {
+            if advertisement.region.len() > crate::advertisement::MAX_REGION_LENGTH {
+                return Err(ConfigError::Invalid(format!(
+                    "advertisement.region must be at most {} bytes",
+                    crate::advertisement::MAX_REGION_LENGTH
+                )));
+            }
+        }
+        for (name, upstream) in &self.upstreams {
+            if upstream.resolver == ResolverKind::Srv && !cfg!(feature = "srv") {
+                return Err(ConfigError::Invalid(format!(
+                    "upstream {}: {}",
+                    name,
+                    crate::features::missing("srv", "resolver = \"srv\"")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings of `proxi client`, kept to what a player needs to set.
+#[cfg(feature = "tunnel")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Local port, or address, the game client connects to.
+    pub local: String,
+    /// Relay address to tunnel to.
+    pub remote: String,
+    /// Obfuscation key shared with the relay, 32 bytes hex encoded.
+    pub key: Option<String>,
+    /// HMAC key shared with the relay, at least 16 bytes hex encoded.
+    pub hmac_key: Option<String>,
+    /// Print a machine readable status line whenever the state changes.
+    pub status_line: bool,
+}
+
+#[cfg(feature = "tunnel")]
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            local: DEFAULT_CLIENT_LOCAL.to_string(),
+            remote: String::new(),
+            key: None,
+            hmac_key: None,
+            status_line: false,
+        }
+    }
+}
+
+#[cfg(feature = "tunnel")]
+impl ClientConfig {
+    /// Defaults, then the client file, then `PROXY_OBFUSCATION_KEY` and
+    /// `PROXY_HMAC_KEY`, then the command line.
+    pub fn from_args(args: &ClientArgs) -> Result<ClientConfig, ConfigError> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+                toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?
+            }
+            None => ClientConfig::default(),
+        };
+
+        if let Some(key) = env_var(ENV_OBFUSCATION_KEY) {
+            config.key = Some(key);
+        }
+        if let Some(key) = env_var(ENV_HMAC_KEY) {
+            config.hmac_key = Some(key);
+        }
+        if let Some(local) = &args.local {
+            config.local = local.clone();
+        }
+        if let Some(remote) = &args.remote {
+            config.remote = remote.clone();
+        }
+        if let Some(key) = &args.key {
+            config.key = Some(key.clone());
+        }
+        if let Some(key) = &args.hmac_key {
+            config.hmac_key = Some(key.clone());
+        }
+        config.status_line |= args.status_line;
+
+        if config.remote.is_empty() {
+            return Err(ConfigError::Invalid("client needs a remote relay address (--remote)".to_string()));
+        }
+        if let Some(key) = &config.key {
+            crate::obfuscation::parse_key(key).map_err(ConfigError::Invalid)?;
+        }
+        if let Some(key) = &config.hmac_key {
+            crate::integrity::parse_key(key).map_err(ConfigError::Invalid)?;
+        }
+        Ok(config)
+    }
+}
+
+/// Reads a config file with its includes merged in.
+///
+/// `include = ["base.toml", ...]` names files relative to the including one.
+/// They are merged in the order listed, each over the previous ones, and the
+/// including file goes on top, so a per-environment file only needs the
+/// settings that differ from the shared base. Tables are merged key by key;
+/// arrays such as `[[listeners]]` replace each other whole. `stack` holds the
+/// files being read to reject include cycles.
+fn read_table(path: &Path, stack: &mut Vec<PathBuf>) -> Result<toml::Table, ConfigError> {
+    let canonical = path
+        .canonicalize()
+        .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    if stack.contains(&canonical) {
+        return Err(ConfigError::IncludeCycle(path.to_path_buf()));
+    }
+
+    let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+    let mut table: toml::Table = toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+    let includes = match table.remove("include") {
+        None => Vec::new(),
+        Some(toml::Value::String(include)) => vec![include],
+        Some(toml::Value::Array(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                toml::Value::String(include) => Ok(include),
+                _ => Err(ConfigError::Invalid(format!(
+                    "include in {} must list file names",
+                    path.display()
+                ))),
+            })
+            .collect::<Result<_, _>>()?,
+        Some(_) => {
+            return Err(ConfigError::Invalid(format!(
+                "include in {} must be a file name or a list of them",
+                path.display()
+            )))
+        }
+    };
+    if includes.is_empty() {
+        return Ok(table);
+    }
+
+    stack.push(canonical);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = toml::Table::new();
+    for include in includes {
+        merge_tables(&mut merged, &read_table(&dir.join(include), stack)?);
+    }
+    stack.pop();
+
+    merge_tables(&mut merged, &table);
+    Ok(merged)
+}
+
+/// Merges `overlay` into `base`: nested tables are merged key by key, any
+/// other value in `overlay` (arrays included) replaces the one in `base`.
+fn merge_tables(base: &mut toml::Table, overlay: &toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge_tables(base, overlay),
+            _ => {
+                base.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Held by tests that set `PROXY_*` variables or read them through `from_cli`.
+#[cfg(test)]
+pub(crate) static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Reads an environment variable, treating unset and empty the same way.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn parse_size(name: &str, value: &str) -> Result<usize, ConfigError> {
+    value
+        .parse()
+        .map_err(|_| ConfigError::Invalid(format!("{} is not a valid size: {}", name, value)))
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownProfile(PathBuf, String),
+    IncludeCycle(PathBuf),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Cannot read config file {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid config file {}: {}", path.display(), e),
+            ConfigError::UnknownProfile(path, name) => {
+                write!(f, "Config file {} has no profile named {}", path.display(), name)
+            }
+            ConfigError::IncludeCycle(path) => write!(f, "Config file {} includes itself", path.display()),
+            ConfigError::Invalid(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// An empty directory of its own for the files of one test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("proxi-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn overrides_stack_before_validation() {
+        let dir = scratch_dir("layers");
+        let path = dir.join("proxy.toml");
+        std::fs::write(
+            &path,
+            "listen = \"0.0.0.0:1\"\ntarget = \"127.0.0.1:2\"\n[buffers]\nmessage_max_size = 5\nread_buffer_size = 512\n",
+        )
+        .unwrap();
+        let _env = ENV_LOCK.lock().unwrap();
+        std::env::set_var(ENV_MESSAGE_MAX_SIZE, "1000");
+        std::env::set_var(ENV_TARGET, "127.0.0.1:3");
+        std::env::set_var(ENV_LISTEN, "0.0.0.0:4");
+        let cli = Cli::parse_from(["proxi", "--config", path.to_str().unwrap(), "--listen", "0.0.0.0:5"]);
+        let config = Config::from_cli(&cli);
+        std::env::remove_var(ENV_MESSAGE_MAX_SIZE);
+        std::env::remove_var(ENV_TARGET);
+        std::env::remove_var(ENV_LISTEN);
+        let config = config.unwrap();
+
+        assert_eq!(config.buffers.channel_capacity, DEFAULT_CHANNEL_CAPACITY);
+        assert_eq!(config.buffers.read_buffer_size, 512);
+        assert_eq!(config.buffers.message_max_size, 1000);
+        assert_eq!(config.target, "127.0.0.1:3");
+        assert_eq!(config.listen, "0.0.0.0:5");
+    }
+
+    /// Writes `files` into a fresh directory and reads the first one.
+    fn read(name: &str, files: &[(&str, &str)]) -> Result<toml::Table, ConfigError> {
+        let dir = scratch_dir(name);
+        for (file, contents) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        read_table(&dir.join(files[0].0), &mut Vec::new())
+    }
+
+    #[test]
+    fn nested_tables_merge_key_by_key() {
+        let table = read(
+            "include-tables",
+            &[
+                ("main.toml", "include = \"base.toml\"\n[buffers]\nchannel_capacity = 8\n"),
+                ("base.toml", "[buffers]\nchannel_capacity = 4\nread_buffer_size = 512\n"),
+            ],
+        )
+        .unwrap();
+        let buffers = table["buffers"].as_table().unwrap();
+        assert_eq!(buffers["channel_capacity"].as_integer(), Some(8));
+        assert_eq!(buffers["read_buffer_size"].as_integer(), Some(512));
+    }
+
+    #[test]
+    fn arrays_are_replaced_whole() {
+        let table = read(
+            "include-arrays",
+            &[
+                ("main.toml", "include = \"base.toml\"\n[[listeners]]\nlisten = \"0.0.0.0:3\"\n"),
+                (
+                    "base.toml",
+                    "[[listeners]]\nlisten = \"0.0.0.0:1\"\n[[listeners]]\nlisten = \"0.0.0.0:2\"\n",
+                ),
+            ],
+        )
+        .unwrap();
+        let listeners = table["listeners"].as_array().unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0]["listen"].as_str(), Some("0.0.0.0:3"));
+    }
+
+    #[test]
+    fn later_includes_win_and_the_including_file_wins_over_all() {
+        let table = read(
+            "include-order",
+            &[
+                ("main.toml", "include = [\"a.toml\", \"b.toml\"]\nlisten = \"main\"\n"),
+                ("a.toml", "listen = \"a\"\ntarget = \"a\"\nmode = \"a\"\n"),
+                ("b.toml", "listen = \"b\"\ntarget = \"b\"\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table["listen"].as_str(), Some("main"));
+        assert_eq!(table["target"].as_str(), Some("b"));
+        assert_eq!(table["mode"].as_str(), Some("a"));
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let table = read(
+            "include-relative",
+            &[
+                ("main.toml", "include = \"shared/base.toml\"\n"),
+                ("shared/base.toml", "include = \"defaults.toml\"\n"),
+                ("shared/defaults.toml", "listen = \"shared\"\n"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(table["listen"].as_str(), Some("shared"));
+    }
+
+    #[test]
+    fn self_include_is_a_cycle() {
+        let result = read("include-self", &[("main.toml", "include = \"main.toml\"\n")]);
+        assert!(matches!(result, Err(ConfigError::IncludeCycle(_))));
+    }
+
+    #[test]
+    fn indirect_include_is_a_cycle() {
+        let result = read(
+            "include-indirect",
+            &[
+                ("main.toml", "include = \"a.toml\"\n"),
+                ("a.toml", "include = \"b.toml\"\n"),
+                ("b.toml", "include = \"main.toml\"\n"),
+            ],
+        );
+        assert!(matches!(result, Err(ConfigError::IncludeCycle(_))));
+    }
+}
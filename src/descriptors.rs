@@ -0,0 +1,169 @@
+//! Surviving file descriptor exhaustion.
+//!
+//! Once the process hits its descriptor limit `accept` fails with EMFILE or
+//! ENFILE straight away, and the client that caused it stays in the backlog,
+//! so a plain retry loop spins. Listeners keep one descriptor in reserve: when
+//! accepting fails for lack of descriptors they log an alert, give the reserve
+//! up to accept and close the waiting client, optionally close the sessions
+//! that have been idle longest, and pause accepting for `descriptors.pause_ms`.
+
+use crate::config::DescriptorsConfig;
+use crate::debug;
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+#[cfg(unix)]
+const NULL_DEVICE: &str = "/dev/null";
+#[cfg(not(unix))]
+const NULL_DEVICE: &str = "NUL";
+
+/// How long to wait for the client behind a failed accept.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Whether accepting failed because the process or the system is out of
+/// descriptors.
+pub fn is_exhausted(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    return matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE));
+    // WSAEMFILE, Windows has no system wide limit.
+    #[cfg(not(unix))]
+    return error.raw_os_error() == Some(10024);
+}
+
+/// Shared by every listener of the process, since they draw on the same
+/// descriptor limit.
+#[derive(Default)]
+pub struct Descriptors {
+    reserve: Mutex<Option<File>>,
+    pub sessions: Sessions,
+}
+
+impl Descriptors {
+    /// Takes the emergency descriptor, if it isn't held already.
+    pub fn reserve(&self) {
+        let mut reserve = self.reserve.lock().unwrap();
+        if reserve.is_none() {
+            match File::open(NULL_DEVICE) {
+                Ok(file) => *reserve = Some(file),
+                Err(e) => debug!("[descriptors] - Couldn't reserve a descriptor: {}", e),
+            }
+        }
+    }
+
+    /// Handles a failed accept on `listener` once `is_exhausted` said so.
+    pub async fn recover(&self, listen: &str, listener: &TcpListener, error: &io::Error, config: &DescriptorsConfig) {
+        eprintln!(
+            "Error: [{}] out of file descriptors ({}), pausing accepts for {}ms",
+            listen, error, config.pause_ms
+        );
+
+        // Frees a descriptor so the client that couldn't be accepted gets
+        // closed instead of waiting in the backlog.
+        self.reserve.lock().unwrap().take();
+        if let Ok(Ok((stream, peer))) = tokio::time::timeout(DRAIN_TIMEOUT, listener.accept()).await {
+            debug!("[{}] Turned away {} while out of file descriptors", listen, peer);
+            drop(stream);
+        }
+
+        if config.shed_sessions > 0 {
+            let shed = self
+                .sessions
+                .shed(config.shed_sessions, Duration::from_secs(config.idle_secs));
+            eprintln!("Error: [{}] closed {} idle session(s) to free file descriptors", listen, shed);
+        }
+
+        tokio::time::sleep(Duration::from_millis(config.pause_ms)).await;
+        self.reserve();
+    }
+}
+
+struct SessionState {
+    /// Milliseconds since `Sessions::epoch` at the last traffic.
+    last_active: AtomicU64,
+    shed: Notify,
+}
+
+/// Sessions in progress, with when they last carried traffic.
+pub struct Sessions {
+    epoch: Instant,
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<SessionState>>>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            epoch: Instant::now(),
+            next_id: AtomicU64::new(0),
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+/// Registration of one session, removed when dropped.
+pub struct Session<'a> {
+    sessions: &'a Sessions,
+    id: u64,
+    state: Arc<SessionState>,
+}
+
+impl Sessions {
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    pub fn register(&self) -> Session<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(SessionState {
+            last_active: AtomicU64::new(self.now()),
+            shed: Notify::new(),
+        });
+        self.active.lock().unwrap().insert(id, Arc::clone(&state));
+        Session {
+            sessions: self,
+            id,
+            state,
+        }
+    }
+
+    /// Asks up to `count` sessions idle for at least `min_idle` to close,
+    /// longest idle first. Returns how many were asked.
+    fn shed(&self, count: usize, min_idle: Duration) -> usize {
+        let cutoff = self.now().saturating_sub(min_idle.as_millis() as u64);
+        let active = self.active.lock().unwrap();
+        let mut idle: Vec<(u64, &Arc<SessionState>)> = active
+            .values()
+            .map(|state| (state.last_active.load(Ordering::Relaxed), state))
+            .filter(|(last_active, _)| *last_active <= cutoff)
+            .collect();
+        idle.sort_by_key(|(last_active, _)| *last_active);
+        for (_, state) in idle.iter().take(count) {
+            state.shed.notify_one();
+        }
+        idle.len().min(count)
+    }
+}
+
+impl Session<'_> {
+    /// Records traffic on the session.
+    pub fn touch(&self) {
+        self.state.last_active.store(self.sessions.now(), Ordering::Relaxed);
+    }
+
+    /// Completes once the session was picked to be closed.
+    pub async fn shed(&self) {
+        self.state.shed.notified().await;
+    }
+}
+
+impl Drop for Session<'_> {
+    fn drop(&mut self) {
+        self.sessions.active.lock().unwrap().remove(&self.id);
+    }
+}
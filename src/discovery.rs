@@ -0,0 +1,257 @@
+//! Resolution of logical upstream names to server addresses.
+//!
+//! An `[upstreams.<name>]` group can give a `name` instead of fixed
+//! `addresses`. The name is handed to the group's resolver when a client
+//! connects, and the answer is cached for `resolver.cache_secs`, so the
+//! servers behind a name can change without touching the proxy config.
+//! SRV answers are cached for their own TTL and carry a priority and weight
+//! per server, so a fleet can be drained or rebalanced purely in DNS.
+
+use crate::config::{Config, ResolverKind};
+use crate::debug;
+use crate::routing::Destination;
+use futures::future::BoxFuture;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::lookup_host;
+
+/// One server behind a name. Only SRV records set `priority` and `weight`.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub address: String,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+impl Target {
+    fn plain(address: String) -> Target {
+        Target {
+            address,
+            priority: 0,
+            weight: 0,
+        }
+    }
+}
+
+/// What a resolver found for a name.
+pub struct Answer {
+    pub targets: Vec<Target>,
+    /// When the answer goes stale, if the source says so; otherwise it is kept
+    /// for `resolver.cache_secs`.
+    pub valid_until: Option<Instant>,
+}
+
+/// Turns a logical name into the servers behind it.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Answer>>;
+}
+
+/// Looks names up in `[resolver.names]`.
+pub struct StaticResolver<'a> {
+    names: &'a BTreeMap<String, Vec<String>>,
+}
+
+impl Resolver for StaticResolver<'_> {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Answer>> {
+        Box::pin(async move {
+            let addresses = self
+                .names
+                .get(name)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in [resolver.names]", name)))?;
+            Ok(Answer {
+                targets: addresses.iter().cloned().map(Target::plain).collect(),
+                valid_until: None,
+            })
+        })
+    }
+}
+
+/// Resolves `host:port` names to every address the system resolver returns.
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Answer>> {
+        Box::pin(async move {
+            Ok(Answer {
+                targets: lookup_host(name).await?.map(|address| Target::plain(address.to_string())).collect(),
+                valid_until: None,
+            })
+        })
+    }
+}
+
+/// Reads the SRV record of a service name such as `_game._tcp.example.com`.
+/// Target hosts are left for the system resolver when connecting.
+#[cfg(feature = "srv")]
+pub struct SrvResolver;
+
+#[cfg(feature = "srv")]
+impl Resolver for SrvResolver {
+    fn resolve<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Answer>> {
+        use hickory_resolver::proto::rr::RData;
+        use hickory_resolver::TokioResolver;
+
+        Box::pin(async move {
+            let resolver = TokioResolver::builder_tokio()
+                .and_then(|builder| builder.build())
+                .map_err(io::Error::other)?;
+            let lookup = resolver.srv_lookup(name).await.map_err(io::Error::other)?;
+            let targets = lookup
+                .answers()
+                .iter()
+                .filter_map(|record| match &record.data {
+                    // A target of "." means the service is not offered there.
+                    RData::SRV(srv) if !srv.target.is_root() => Some(Target {
+                        address: format!("{}:{}", srv.target.to_utf8().trim_end_matches('.'), srv.port),
+                        priority: srv.priority,
+                        weight: srv.weight,
+                    }),
+                    _ => None,
+                })
+                .collect();
+            Ok(Answer {
+                targets,
+                valid_until: Some(lookup.valid_until()),
+            })
+        })
+    }
+}
+
+/// Resolved servers by resolver and name, with when they go stale.
+type Cache = HashMap<(ResolverKind, String), (Instant, Vec<Target>)>;
+
+/// Resolves logical destinations, caching the answers.
+#[derive(Default)]
+pub struct Discovery {
+    cache: Mutex<Cache>,
+}
+
+impl Discovery {
+    /// Fills in the addresses of a destination that only has a logical name,
+    /// in the order they should be tried.
+    pub async fn resolve(&self, config: &Config, mut destination: Destination) -> io::Result<Destination> {
+        let Some((kind, name)) = destination.lookup.clone() else {
+            return Ok(destination);
+        };
+        let key = (kind, name);
+
+        let cached = self.cache.lock().unwrap().get(&key).cloned();
+        if let Some((valid_until, targets)) = cached {
+            if Instant::now() < valid_until {
+                destination.addresses = order(targets);
+                return Ok(destination);
+            }
+        }
+
+        let (kind, name) = &key;
+        let answer = match kind {
+            ResolverKind::Static => StaticResolver { names: &config.resolver.names }.resolve(name).await,
+            ResolverKind::Dns => DnsResolver.resolve(name).await,
+            #[cfg(feature = "srv")]
+            ResolverKind::Srv => SrvResolver.resolve(name).await,
+            // The config loader refuses srv upstreams in builds without the feature.
+            #[cfg(not(feature = "srv"))]
+            ResolverKind::Srv => Err(io::Error::other(crate::features::missing("srv", "resolver = \"srv\""))),
+        }?;
+        if answer.targets.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} resolved to no addresses", name)));
+        }
+        debug!(
+            "[discovery] - {} resolved to {}",
+            name,
+            answer
+                .targets
+                .iter()
+                .map(|target| match kind {
+                    ResolverKind::Srv => format!(
+                        "{} (priority {}, weight {})",
+                        target.address, target.priority, target.weight
+                    ),
+                    _ => target.address.clone(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let valid_until = answer
+            .valid_until
+            .unwrap_or_else(|| Instant::now() + Duration::from_secs(config.resolver.cache_secs));
+        self.cache.lock().unwrap().insert(key, (valid_until, answer.targets.clone()));
+        destination.addresses = order(answer.targets);
+        Ok(destination)
+    }
+}
+
+/// Orders targets the way RFC 2782 asks clients to try them: lowest priority
+/// first, and within a priority a weighted random pick for each position, so
+/// heavier targets tend to come first. Targets without priorities and weights
+/// keep their order.
+fn order(targets: Vec<Target>) -> Vec<String> {
+    order_with(targets, &mut rand::thread_rng())
+}
+
+fn order_with(mut targets: Vec<Target>, rng: &mut impl Rng) -> Vec<String> {
+    // Stable, so weight 0 targets stay ahead of the others as the RFC wants.
+    targets.sort_by_key(|target| (target.priority, target.weight != 0));
+
+    let mut ordered = Vec::with_capacity(targets.len());
+    while !targets.is_empty() {
+        let priority = targets[0].priority;
+        let group = targets.iter().take_while(|target| target.priority == priority).count();
+        let total: u32 = targets[..group].iter().map(|target| u32::from(target.weight)).sum();
+        let pick = rng.gen_range(0..=total);
+
+        let mut running = 0;
+        let chosen = targets[..group]
+            .iter()
+            .position(|target| {
+                running += u32::from(target.weight);
+                running >= pick
+            })
+            .unwrap_or(0);
+        ordered.push(targets.remove(chosen).address);
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    fn srv(address: &str, priority: u16, weight: u16) -> Target {
+        Target {
+            address: address.to_string(),
+            priority,
+            weight,
+        }
+    }
+
+    #[test]
+    fn priorities_are_tried_in_ascending_order() {
+        let targets = vec![srv("c", 30, 5), srv("a", 10, 5), srv("b", 20, 0), srv("a", 10, 50)];
+        for _ in 0..20 {
+            let ordered = order(targets.clone());
+            assert_eq!(&ordered[..2], ["a", "a"]);
+            assert_eq!(&ordered[2..], ["b", "c"]);
+        }
+    }
+
+    #[test]
+    fn weight_zero_targets_come_first_within_a_priority() {
+        let targets = vec![srv("heavy", 1, 100), srv("zero", 1, 0), srv("light", 1, 1), srv("backup", 2, 0)];
+        // Always picking the lowest running weight lands on the weight 0 target.
+        let ordered = order_with(targets, &mut StepRng::new(0, 0));
+        assert_eq!(ordered, ["zero", "heavy", "light", "backup"]);
+    }
+
+    #[test]
+    fn plain_targets_keep_their_order() {
+        let addresses = ["127.0.0.1:3", "127.0.0.1:1", "127.0.0.1:2"];
+        let targets = addresses.iter().map(|address| Target::plain(address.to_string())).collect();
+        assert_eq!(order(targets), addresses);
+    }
+}
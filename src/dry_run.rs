@@ -0,0 +1,76 @@
+//! `--dry-run`: prints the effective configuration without starting the proxy.
+
+use crate::check::resolve;
+use crate::config::Config;
+use crate::discovery::Discovery;
+use crate::routing;
+
+const REDACTED: &str = "<redacted>";
+
+pub async fn run(config: &Config) {
+    println!("# Effective configuration (defaults < file < environment < command line)");
+    match toml::to_string(&redacted(config)) {
+        Ok(text) => print!("{}", text),
+        Err(e) => eprintln!("Error: configuration can't be printed: {}", e),
+    }
+
+    println!();
+    println!("# Resolved addresses");
+    for listener in config.listeners() {
+        println!("listener {}", listener.name());
+        println!("  listen {}", describe(&listener.listen).await);
+        // validate() already rejected listeners without a usable destination.
+        if let Ok(destination) = routing::resolve(config, &listener) {
+            if let Some((_, name)) = &destination.lookup {
+                match Discovery::default().resolve(config, destination.clone()).await {
+                    Ok(resolved) => println!("  name {} -> {}", name, resolved.addresses.join(", ")),
+                    Err(e) => println!("  name {} -> error: {}", name, e),
+                }
+                continue;
+            }
+            for address in &destination.addresses {
+                println!("  upstream {}", describe(address).await);
+            }
+        }
+    }
+    if let Some(advertisement) = &config.advertisement {
+        println!("advertisement");
+        println!("  listen {}", describe(&advertisement.listen).await);
+    }
+}
+
+/// `address -> resolved, ...`, or the reason it didn't resolve.
+async fn describe(address: &str) -> String {
+    match resolve(address).await {
+        Ok(resolved) => {
+            let resolved: Vec<String> = resolved.iter().map(ToString::to_string).collect();
+            format!("{} -> {}", address, resolved.join(", "))
+        }
+        Err(e) => format!("{} -> error: {}", address, e),
+    }
+}
+
+/// Copy of `config` with the shared keys masked, so the output can be pasted
+/// into a bug report.
+fn redacted(config: &Config) -> Config {
+    fn mask(key: &mut String) {
+        if !key.is_empty() {
+            *key = REDACTED.to_string();
+        }
+    }
+
+    let mut config = config.clone();
+    mask(&mut config.obfuscation.key);
+    mask(&mut config.integrity.key);
+    config.integrity.previous_keys.iter_mut().for_each(mask);
+    for listener in &mut config.listeners {
+        if let Some(obfuscation) = &mut listener.obfuscation {
+            mask(&mut obfuscation.key);
+        }
+        if let Some(integrity) = &mut listener.integrity {
+            mask(&mut integrity.key);
+            integrity.previous_keys.iter_mut().for_each(mask);
+        }
+    }
+    config
+}
@@ -0,0 +1,46 @@
+//! Optional subsystems compiled into this build.
+//!
+//! Relay nodes that don't need them can be built with
+//! `--no-default-features` and only the features they use, which keeps the
+//! binary small and the dependency tree short enough to audit.
+
+/// Every optional feature: cargo feature name, whether it is compiled in and
+/// what it provides.
+pub const FEATURES: &[(&str, bool, &str)] = &[
+    (
+        "compression",
+        cfg!(feature = "compression"),
+        "zstd compression on the client leg ([compression])",
+    ),
+    (
+        "tunnel",
+        cfg!(feature = "tunnel"),
+        "obfuscated and HMAC authenticated tunnels ([obfuscation], [integrity], proxi client)",
+    ),
+    (
+        "srv",
+        cfg!(feature = "srv"),
+        "DNS SRV upstream discovery (resolver = \"srv\")",
+    ),
+];
+
+pub fn enabled() -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled, _)| *enabled)
+        .map(|(name, _, _)| *name)
+        .collect()
+}
+
+/// `--features`: prints which optional subsystems this binary has.
+pub fn report() {
+    println!("proxi {}", env!("CARGO_PKG_VERSION"));
+    for (name, enabled, description) in FEATURES {
+        println!("  {:<12} {:<3}  {}", name, if *enabled { "yes" } else { "no" }, description);
+    }
+}
+
+/// Error for a config section that needs a feature this build lacks.
+pub fn missing(feature: &str, section: &str) -> String {
+    format!("{} needs the {} feature, which this build was compiled without", section, feature)
+}
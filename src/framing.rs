@@ -0,0 +1,135 @@
+//! Splitting the client's byte stream into packets.
+//!
+//! A read from the client ends wherever TCP happened to cut the stream, so a
+//! packet can arrive in pieces or several at once. Features that look at
+//! packet headers read the client leg through a `PacketCodec`, which frames
+//! it by the u16 length header every packet starts with.
+
+use crate::compression::ClientCodec;
+use crate::LENGTH_HEADER_SIZE;
+use bytes::BytesMut;
+use tokio::io;
+use tokio_util::codec::{Decoder, LengthDelimitedCodec};
+
+pub struct PacketCodec {
+    inner: ClientCodec,
+    /// Decoded bytes not yet making up a whole packet.
+    pending: BytesMut,
+    /// None passes the chunks of `inner` on as they come.
+    lengths: Option<LengthDelimitedCodec>,
+}
+
+impl PacketCodec {
+    /// Passes chunks on unframed.
+    pub fn chunks(inner: ClientCodec) -> PacketCodec {
+        PacketCodec {
+            inner,
+            pending: BytesMut::new(),
+            lengths: None,
+        }
+    }
+
+    /// Yields whole packets, length header included, of at most
+    /// `max_packet_size` bytes.
+    pub fn packets(inner: ClientCodec, max_packet_size: usize) -> PacketCodec {
+        let lengths = LengthDelimitedCodec::builder()
+            .little_endian()
+            .length_field_length(LENGTH_HEADER_SIZE)
+            // The length counts the bytes after the header; keep the header in the frame.
+            .length_adjustment(LENGTH_HEADER_SIZE as isize)
+            .num_skip(0)
+            .max_frame_length(max_packet_size)
+            .new_codec();
+        PacketCodec {
+            inner,
+            pending: BytesMut::new(),
+            lengths: Some(lengths),
+        }
+    }
+}
+
+impl Decoder for PacketCodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let Some(lengths) = &mut self.lengths else {
+            return self.inner.decode(src);
+        };
+        while let Some(chunk) = self.inner.decode(src)? {
+            if self.pending.is_empty() {
+                self.pending = chunk;
+            } else {
+                self.pending.extend_from_slice(&chunk);
+            }
+        }
+        lengths.decode(&mut self.pending)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if self.lengths.is_none() {
+            return self.inner.decode_eof(src);
+        }
+        if let Some(packet) = self.decode(src)? {
+            return Ok(Some(packet));
+        }
+        // The client left in the middle of a packet; its start still goes upstream.
+        if self.pending.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.pending.split()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::BytesCodec;
+
+    fn codec() -> PacketCodec {
+        PacketCodec::packets(ClientCodec::Plain(BytesCodec::new()), 64)
+    }
+
+    #[test]
+    fn split_packet_is_joined() {
+        let mut codec = codec();
+        let mut src = BytesMut::from(&[3, 0, 1][..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&[2, 3]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], [3, 0, 1, 2, 3]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn coalesced_packets_come_out_one_by_one() {
+        let mut codec = codec();
+        let mut src = BytesMut::from(&[1, 0, 7, 2, 0, 8, 9][..]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], [1, 0, 7]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], [2, 0, 8, 9]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn eof_mid_packet_passes_its_start_on() {
+        let mut codec = codec();
+        let mut src = BytesMut::from(&[1, 0, 7, 5, 0, 1][..]);
+        assert_eq!(&codec.decode_eof(&mut src).unwrap().unwrap()[..], [1, 0, 7]);
+        assert_eq!(&codec.decode_eof(&mut src).unwrap().unwrap()[..], [5, 0, 1]);
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn oversized_packet_is_an_error() {
+        let mut codec = codec();
+        let mut src = BytesMut::from(&[100, 0, 1][..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn chunks_pass_through_unframed() {
+        let mut codec = PacketCodec::chunks(ClientCodec::Plain(BytesCodec::new()));
+        let mut src = BytesMut::from(&[3, 0, 1][..]);
+        assert_eq!(&codec.decode(&mut src).unwrap().unwrap()[..], [3, 0, 1]);
+    }
+}
@@ -0,0 +1,302 @@
+//! `proxi generate-config`: writes a commented default configuration file.
+//!
+//! Keys and default values come from serializing the config structs and the
+//! comments from their doc comments, so the file always matches what the
+//! loader accepts. Sections that are off by
+//! default (listeners, upstream groups, advertisement) are rendered from an
+//! example configuration and commented out. The example listener carries
+//! every per-listener override table, documented like the global sections.
+
+use crate::config::{AdvertisementConfig, Config, ListenerConfig, ResolverKind, UpstreamConfig};
+use std::path::Path;
+use tokio::io;
+use toml::{Table, Value};
+
+const HEADER: &str = "\
+# proxi configuration
+#
+# Every setting is optional; the values below are the defaults. PROXY_*
+# environment variables override this file and command line options
+# override both.
+";
+
+const FOOTER: &str = "
+# Other files can be merged in with include = [\"base.toml\", ...] at the top
+# of this file. Paths are relative to this file; later includes override
+# earlier ones and this file overrides them all.
+#
+# Profiles bundle overrides selected with --profile (or PROXY_PROFILE). A
+# profile is merged over the rest of this file and only lists what differs.
+#
+# [profiles.debug.logging]
+# level = \"trace\"
+#
+# [profiles.production.inspection]
+# enabled = false
+";
+
+/// Source of the config structs, whose field doc comments are the comments of
+/// the generated file.
+const CONFIG_SOURCE: &str = include_str!("config.rs");
+
+/// Width comments are wrapped at, `# ` included.
+const COMMENT_WIDTH: usize = 92;
+
+/// Tables whose keys are user chosen names rather than fields.
+const NAMED_TABLES: &[&str] = &["upstreams", "resolver.names"];
+
+/// Tables whose sub-tables override the global section of the same name.
+const OVERRIDE_TABLES: &[&str] = &["listeners"];
+
+pub async fn run(output: Option<&Path>, force: bool) -> io::Result<()> {
+    let contents = render();
+    match output {
+        Some(path) => {
+            if path.exists() && !force {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} already exists, use --force to overwrite it", path.display()),
+                ));
+            }
+            tokio::fs::write(path, contents).await
+        }
+        None => {
+            print!("{}", contents);
+            Ok(())
+        }
+    }
+}
+
+pub fn render() -> String {
+    let defaults = to_table(&Config::default());
+    let example = to_table(&example_config());
+
+    let mut out = String::from(HEADER);
+    out.push('\n');
+    render_table(&mut out, "", "", &example, Some(&defaults));
+    out.push_str(FOOTER);
+    out
+}
+
+/// The defaults plus one of everything that is empty or off by default.
+fn example_config() -> Config {
+    let mut config = Config::default();
+    config.listeners.push(ListenerConfig {
+        name: Some("game".to_string()),
+        listen: "0.0.0.0:7172".to_string(),
+        target: None,
+        upstream: Some("game".to_string()),
+        pacing: Some(Default::default()),
+        waiting_room: Some(Default::default()),
+        compression: Some(Default::default()),
+        obfuscation: Some(Default::default()),
+        integrity: Some(Default::default()),
+        knocking: Some(Default::default()),
+    });
+    config.upstreams.insert(
+        "game".to_string(),
+        UpstreamConfig {
+            addresses: vec!["127.0.0.1:7173".to_string(), "127.0.0.1:7174".to_string()],
+            name: None,
+            resolver: ResolverKind::default(),
+        },
+    );
+    config.upstreams.insert(
+        "staging".to_string(),
+        UpstreamConfig {
+            addresses: Vec::new(),
+            name: Some("game.internal".to_string()),
+            resolver: ResolverKind::Static,
+        },
+    );
+    config.resolver.names.insert("game.internal".to_string(), vec!["127.0.0.1:7173".to_string()]);
+    config.advertisement = Some(AdvertisementConfig {
+        listen: "0.0.0.0:7170".to_string(),
+        region: "eu-west".to_string(),
+    });
+    config
+}
+
+fn to_table(config: &Config) -> Table {
+    match Value::try_from(config) {
+        Ok(Value::Table(table)) => table,
+        _ => unreachable!("Config always serializes to a table"),
+    }
+}
+
+fn is_table_array(value: &Value) -> bool {
+    matches!(value, Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_table))
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+/// Dotted path of the field `value` is documented on. Entries of named tables
+/// are `*`.
+fn sub_doc_path(doc_path: &str, key: &str, value: &Value) -> String {
+    if NAMED_TABLES.contains(&doc_path) {
+        join(doc_path, "*")
+    } else if OVERRIDE_TABLES.contains(&doc_path) && value.is_table() {
+        key.to_string()
+    } else {
+        join(doc_path, key)
+    }
+}
+
+/// Renders `example`, commenting out everything that `defaults` doesn't have.
+fn render_table(out: &mut String, path: &str, doc_path: &str, example: &Table, defaults: Option<&Table>) {
+    for (key, value) in example {
+        if value.is_table() || is_table_array(value) {
+            continue;
+        }
+        let enabled = defaults.is_some_and(|defaults| defaults.contains_key(key));
+        push_doc(out, &sub_doc_path(doc_path, key, value));
+        push_line(out, &format!("{} = {}", toml_key(key), value), enabled);
+    }
+
+    for (key, value) in example {
+        let sub_path = join(path, key);
+        let sub_doc_path = sub_doc_path(doc_path, key, value);
+
+        if let Value::Table(table) = value {
+            let sub_defaults = defaults.and_then(|defaults| defaults.get(key)).and_then(Value::as_table);
+            out.push('\n');
+            if OVERRIDE_TABLES.contains(&doc_path) {
+                push_doc(out, &join(doc_path, key));
+            } else {
+                push_doc(out, &sub_doc_path);
+            }
+            push_line(out, &format!("[{}]", sub_path), sub_defaults.is_some());
+            render_table(out, &sub_path, &sub_doc_path, table, sub_defaults);
+        } else if let (true, Value::Array(items)) = (is_table_array(value), value) {
+            out.push('\n');
+            push_doc(out, &sub_doc_path);
+            for item in items.iter().filter_map(Value::as_table) {
+                push_line(out, &format!("[[{}]]", sub_path), false);
+                render_table(out, &sub_path, &sub_doc_path, item, None);
+            }
+        }
+    }
+}
+
+/// `key` as written in TOML, quoted unless it is a bare key.
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+fn push_doc(out: &mut String, doc_path: &str) {
+    let Some(doc) = field_doc(doc_path) else {
+        return;
+    };
+    let mut line = String::from("#");
+    for word in doc.split_whitespace() {
+        if line.len() + 1 + word.len() > COMMENT_WIDTH && line.len() > 1 {
+            out.push_str(&line);
+            out.push('\n');
+            line.truncate(1);
+        }
+        line.push(' ');
+        line.push_str(word);
+    }
+    out.push_str(&line);
+    out.push('\n');
+}
+
+/// Doc comment of the config field at `doc_path`, found by following the
+/// field types down from `Config`.
+fn field_doc(doc_path: &str) -> Option<String> {
+    let mut struct_name = Some("Config");
+    let mut doc = None;
+    for segment in doc_path.split('.') {
+        // Entries of a named table have the map's value type.
+        if segment == "*" {
+            doc = None;
+            continue;
+        }
+        let (field_doc, field_type) = find_field(struct_name?, segment)?;
+        doc = Some(field_doc);
+        struct_name = field_type
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .rfind(|name| name.ends_with("Config"));
+    }
+    doc.filter(|doc| !doc.is_empty())
+}
+
+/// Doc comment and type of `field` in `struct_name`.
+fn find_field(struct_name: &str, field: &str) -> Option<(String, &'static str)> {
+    let start = CONFIG_SOURCE.find(&format!("pub struct {} {{", struct_name))?;
+    let mut doc = Vec::new();
+    for line in CONFIG_SOURCE[start..].lines().skip(1).map(str::trim) {
+        if line == "}" {
+            break;
+        }
+        if let Some(text) = line.strip_prefix("///") {
+            doc.push(text.trim());
+        } else if let Some(declaration) = line.strip_prefix("pub ") {
+            if let Some((name, field_type)) = declaration.split_once(": ") {
+                if name == field {
+                    return Some((doc.join(" "), field_type.trim_end_matches(',')));
+                }
+            }
+            doc.clear();
+        }
+    }
+    None
+}
+
+fn push_line(out: &mut String, line: &str, enabled: bool) {
+    if !enabled {
+        out.push_str("# ");
+    }
+    out.push_str(line);
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serialized keys under `table` without a doc comment.
+    fn undocumented(doc_path: &str, table: &Table, missing: &mut Vec<String>) {
+        for (key, value) in table {
+            let path = sub_doc_path(doc_path, key, value);
+            match value {
+                Value::Table(table) => undocumented(&path, table, missing),
+                Value::Array(items) if is_table_array(value) => {
+                    for item in items.iter().filter_map(Value::as_table) {
+                        undocumented(&path, item, missing);
+                    }
+                }
+                _ if NAMED_TABLES.contains(&doc_path) => {}
+                _ => {
+                    if field_doc(&path).is_none() {
+                        missing.push(path);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_key_is_documented() {
+        let mut missing = Vec::new();
+        undocumented("", &to_table(&example_config()), &mut missing);
+        assert!(missing.is_empty(), "keys without a doc comment: {:?}", missing);
+    }
+
+    #[test]
+    fn rendered_file_parses_as_the_defaults() {
+        let rendered: Config = toml::from_str(&render()).unwrap();
+        assert_eq!(to_table(&rendered), to_table(&Config::default()));
+    }
+}
@@ -0,0 +1,484 @@
+//! Frame integrity between paired proxies.
+//!
+//! When a relay and a `proxi client` share an HMAC key, the tunnel between
+//! them is cut into frames carrying a truncated HMAC-SHA256 tag:
+//!
+//! `[u32 payload length][payload][16 byte tag]`
+//!
+//! The tag covers both sides' nonces, the direction, a frame counter and the
+//! payload, so tampered, reordered or replayed frames fail verification,
+//! including a whole recorded session played back to the relay.
+//! A failed frame is reported as a security event and closes the connection
+//! instead of feeding corrupted data to the game.
+//!
+//! The connecting side opens with an 8 byte random nonce followed by a tag
+//! over that nonce proving which key it holds, sent after the obfuscation
+//! handshake when both are enabled. The relay answers with a fresh nonce of
+//! its own and a tag over both, so every session is keyed to a nonce the
+//! client could not have seen before. The relay accepts any of its configured
+//! keys and the session keeps that key until it ends, so keys are rotated by
+//! moving the old one to `previous_keys`, reloading, updating the clients and
+//! finally dropping the old key.
+
+use bytes::{Buf, BufMut, BytesMut};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LENGTH: usize = 8;
+const LENGTH_PREFIX: usize = 4;
+const TAG_LENGTH: usize = 16;
+const MAX_PAYLOAD: usize = 64 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const HELLO_CONTEXT: &[u8] = b"proxi integrity hello";
+const REPLY_CONTEXT: &[u8] = b"proxi integrity reply";
+
+/// Parses a hex encoded key of at least 16 bytes.
+pub fn parse_key(key: &str) -> Result<Vec<u8>, String> {
+    let bytes = hex::decode(key).map_err(|e| format!("hmac key is not valid hex: {}", e))?;
+    if bytes.len() < 16 {
+        return Err("hmac key must be at least 16 bytes (32 hex characters)".to_string());
+    }
+    Ok(bytes)
+}
+
+/// The nonces of both sides of a session.
+#[derive(Clone, Copy)]
+struct Nonces {
+    client: u64,
+    relay: u64,
+}
+
+impl Nonces {
+    fn update(&self, mac: &mut HmacSha256) {
+        mac.update(&self.client.to_le_bytes());
+        mac.update(&self.relay.to_le_bytes());
+    }
+}
+
+struct Signer {
+    mac: HmacSha256,
+    nonces: Nonces,
+    direction: u8,
+    counter: u64,
+}
+
+impl Signer {
+    fn new(key: &[u8], nonces: Nonces, direction: u8) -> Self {
+        Signer {
+            mac: HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length"),
+            nonces,
+            direction,
+            counter: 0,
+        }
+    }
+
+    /// Tag for the next frame; advances the frame counter.
+    fn tag(&mut self, payload: &[u8]) -> [u8; TAG_LENGTH] {
+        let mut mac = self.mac.clone();
+        self.nonces.update(&mut mac);
+        mac.update(&[self.direction]);
+        mac.update(&self.counter.to_le_bytes());
+        mac.update(payload);
+        self.counter += 1;
+
+        let mut tag = [0u8; TAG_LENGTH];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LENGTH]);
+        tag
+    }
+}
+
+/// Tag sent with the nonce so the relay can tell which key the client uses.
+fn hello_tag(key: &[u8], nonce: u64) -> [u8; TAG_LENGTH] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(HELLO_CONTEXT);
+    mac.update(&nonce.to_le_bytes());
+    let mut tag = [0u8; TAG_LENGTH];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LENGTH]);
+    tag
+}
+
+/// Tag sent with the relay's nonce, proving the relay holds the key too.
+fn reply_tag(key: &[u8], nonces: Nonces) -> [u8; TAG_LENGTH] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(REPLY_CONTEXT);
+    nonces.update(&mut mac);
+    let mut tag = [0u8; TAG_LENGTH];
+    tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LENGTH]);
+    tag
+}
+
+/// A stream that frames and authenticates everything passing through it, or
+/// passes it through untouched when no key is configured.
+pub struct Stream<S> {
+    inner: S,
+    signers: Option<(Signer, Signer)>,
+    read_buf: BytesMut,
+    plain: BytesMut,
+    write_buf: BytesMut,
+}
+
+impl<S> Stream<S> {
+    pub fn plain(inner: S) -> Self {
+        Stream {
+            inner,
+            signers: None,
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    fn keyed(inner: S, key: &[u8], nonces: Nonces, initiator: bool) -> Self {
+        let (write_direction, read_direction) = if initiator { (0, 1) } else { (1, 0) };
+        let mut stream = Stream::plain(inner);
+        stream.signers = Some((
+            Signer::new(key, nonces, read_direction),
+            Signer::new(key, nonces, write_direction),
+        ));
+        stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    /// Proxy side: reads the peer's nonce, picks the key it proves to hold
+    /// out of `keys` and answers with a nonce of its own. Passes traffic
+    /// through when `keys` is empty.
+    pub async fn accept(mut inner: S, keys: &[Vec<u8>]) -> io::Result<Self> {
+        if keys.is_empty() {
+            return Ok(Stream::plain(inner));
+        }
+        let mut hello = [0u8; NONCE_LENGTH + TAG_LENGTH];
+        timeout(HANDSHAKE_TIMEOUT, inner.read_exact(&mut hello))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no integrity nonce received"))??;
+        let nonce = u64::from_le_bytes(hello[..NONCE_LENGTH].try_into().unwrap());
+        let Some(key) = keys
+            .iter()
+            .find(|key| constant_time_eq(&hello_tag(key, nonce), &hello[NONCE_LENGTH..]))
+        else {
+            eprintln!("Security: tunnel peer does not hold any accepted HMAC key, closing connection");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown integrity key"));
+        };
+
+        let nonces = Nonces {
+            client: nonce,
+            relay: rand::thread_rng().next_u64(),
+        };
+        let mut reply = [0u8; NONCE_LENGTH + TAG_LENGTH];
+        reply[..NONCE_LENGTH].copy_from_slice(&nonces.relay.to_le_bytes());
+        reply[NONCE_LENGTH..].copy_from_slice(&reply_tag(key, nonces));
+        inner.write_all(&reply).await?;
+        Ok(Stream::keyed(inner, key, nonces, false))
+    }
+
+    /// Connecting side: sends a fresh nonce to the peer and waits for the
+    /// peer's own.
+    pub async fn connect(mut inner: S, key: Option<&[u8]>) -> io::Result<Self> {
+        let Some(key) = key else {
+            return Ok(Stream::plain(inner));
+        };
+        let nonce = rand::thread_rng().next_u64();
+        let mut hello = [0u8; NONCE_LENGTH + TAG_LENGTH];
+        hello[..NONCE_LENGTH].copy_from_slice(&nonce.to_le_bytes());
+        hello[NONCE_LENGTH..].copy_from_slice(&hello_tag(key, nonce));
+        inner.write_all(&hello).await?;
+
+        let mut reply = [0u8; NONCE_LENGTH + TAG_LENGTH];
+        timeout(HANDSHAKE_TIMEOUT, inner.read_exact(&mut reply))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no integrity nonce received"))??;
+        let nonces = Nonces {
+            client: nonce,
+            relay: u64::from_le_bytes(reply[..NONCE_LENGTH].try_into().unwrap()),
+        };
+        if !constant_time_eq(&reply_tag(key, nonces), &reply[NONCE_LENGTH..]) {
+            eprintln!("Security: tunnel peer does not hold the HMAC key, closing connection");
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown integrity key"));
+        }
+        Ok(Stream::keyed(inner, key, nonces, true))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Stream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some((reader, _)) = this.signers.as_mut() else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+
+        while this.plain.is_empty() {
+            if this.read_buf.len() >= LENGTH_PREFIX {
+                let length = u32::from_le_bytes(this.read_buf[..LENGTH_PREFIX].try_into().unwrap()) as usize;
+                if length > MAX_PAYLOAD {
+                    eprintln!("Security: tunnel frame of {} bytes exceeds the limit, closing connection", length);
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "oversized tunnel frame")));
+                }
+                if this.read_buf.len() >= LENGTH_PREFIX + length + TAG_LENGTH {
+                    this.read_buf.advance(LENGTH_PREFIX);
+                    let payload = this.read_buf.split_to(length);
+                    let tag = this.read_buf.split_to(TAG_LENGTH);
+                    if !constant_time_eq(&reader.tag(&payload), &tag) {
+                        eprintln!(
+                            "Security: tunnel frame {} failed HMAC verification, closing connection",
+                            reader.counter - 1
+                        );
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "tunnel frame failed integrity check",
+                        )));
+                    }
+                    this.plain = payload;
+                    continue;
+                }
+            }
+
+            let mut chunk = [0u8; 8 * 1024];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated tunnel frame")));
+            }
+            this.read_buf.extend_from_slice(chunk_buf.filled());
+        }
+
+        let len = this.plain.len().min(buf.remaining());
+        buf.put_slice(&this.plain.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.signers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Only one frame is buffered at a time, which bounds memory use.
+        ready!(this.poll_drain(cx))?;
+
+        let payload = &buf[..buf.len().min(MAX_PAYLOAD)];
+        let (_, writer) = this.signers.as_mut().unwrap();
+        let tag = writer.tag(payload);
+        this.write_buf.reserve(LENGTH_PREFIX + payload.len() + TAG_LENGTH);
+        this.write_buf.put_u32_le(payload.len() as u32);
+        this.write_buf.put_slice(payload);
+        this.write_buf.put_slice(&tag);
+
+        // The frame is accepted either way; whatever is left goes out on the next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(payload.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::IntegrityConfig;
+    use tokio::io::{duplex, DuplexStream};
+
+    const KEY: &[u8] = &[7; 16];
+    const OTHER_KEY: &[u8] = &[9; 16];
+
+    /// Runs the handshake between a client holding `key` and a relay
+    /// accepting `keys`.
+    async fn pair(
+        key: &[u8],
+        keys: &[Vec<u8>],
+    ) -> (io::Result<Stream<DuplexStream>>, io::Result<Stream<DuplexStream>>) {
+        let (client, relay) = duplex(4 * MAX_PAYLOAD);
+        tokio::join!(Stream::connect(client, Some(key)), Stream::accept(relay, keys))
+    }
+
+    fn hello(key: &[u8], nonce: u64) -> Vec<u8> {
+        let mut hello = nonce.to_le_bytes().to_vec();
+        hello.extend_from_slice(&hello_tag(key, nonce));
+        hello
+    }
+
+    /// Frames `payloads` the way a client of the session `nonces` sends them.
+    async fn client_frames(nonces: Nonces, payloads: &[&[u8]]) -> Vec<u8> {
+        let mut stream = Stream::keyed(Vec::new(), KEY, nonces, true);
+        for payload in payloads {
+            stream.write_all(payload).await.unwrap();
+        }
+        stream.inner
+    }
+
+    #[tokio::test]
+    async fn round_trip_with_matching_key() {
+        let (client, relay) = pair(KEY, &[KEY.to_vec()]).await;
+        let (mut client, mut relay) = (client.unwrap(), relay.unwrap());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        relay.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        relay.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+    }
+
+    #[tokio::test]
+    async fn accept_rejects_the_wrong_key() {
+        let (client, relay) = pair(OTHER_KEY, &[KEY.to_vec()]).await;
+        assert_eq!(relay.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_rejects_a_relay_with_the_wrong_key() {
+        let (client, mut relay) = duplex(1024);
+        let fake_relay = async {
+            let mut hello = [0u8; NONCE_LENGTH + TAG_LENGTH];
+            relay.read_exact(&mut hello).await.unwrap();
+            let nonces = Nonces {
+                client: u64::from_le_bytes(hello[..NONCE_LENGTH].try_into().unwrap()),
+                relay: 1,
+            };
+            relay.write_all(&nonces.relay.to_le_bytes()).await.unwrap();
+            relay.write_all(&reply_tag(OTHER_KEY, nonces)).await.unwrap();
+        };
+        let (client, _) = tokio::join!(Stream::connect(client, Some(KEY)), fake_relay);
+        assert_eq!(client.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn client_on_a_previous_key_still_connects() {
+        let config = IntegrityConfig {
+            enabled: true,
+            key: hex::encode(OTHER_KEY),
+            previous_keys: vec![hex::encode(KEY)],
+        };
+        let (client, relay) = pair(KEY, &config.keys().unwrap()).await;
+        let (mut client, mut relay) = (client.unwrap(), relay.unwrap());
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        relay.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        relay.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+    }
+
+    #[tokio::test]
+    async fn key_in_neither_list_is_refused() {
+        let config = IntegrityConfig {
+            enabled: true,
+            key: hex::encode(OTHER_KEY),
+            previous_keys: vec![hex::encode([8u8; 16])],
+        };
+        let (client, relay) = pair(KEY, &config.keys().unwrap()).await;
+        assert_eq!(relay.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert!(client.is_err());
+    }
+
+    #[tokio::test]
+    async fn flipped_payload_bit_fails_verification() {
+        let nonces = Nonces { client: 1, relay: 2 };
+        let mut frames = client_frames(nonces, &[b"payload"]).await;
+        frames[LENGTH_PREFIX] ^= 1;
+
+        let (mut tamperer, relay) = duplex(1024);
+        tamperer.write_all(&frames).await.unwrap();
+        let mut relay = Stream::keyed(relay, KEY, nonces, false);
+        let mut received = [0u8; 7];
+        let e = relay.read_exact(&mut received).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn replayed_session_fails_against_a_fresh_relay_nonce() {
+        let keys = [KEY.to_vec()];
+        let hello = hello(KEY, 42);
+
+        // Record a genuine session.
+        let (mut client, relay) = duplex(1024);
+        client.write_all(&hello).await.unwrap();
+        let mut relay = Stream::accept(relay, &keys).await.unwrap();
+        let mut reply = [0u8; NONCE_LENGTH + TAG_LENGTH];
+        client.read_exact(&mut reply).await.unwrap();
+        let nonces = Nonces {
+            client: 42,
+            relay: u64::from_le_bytes(reply[..NONCE_LENGTH].try_into().unwrap()),
+        };
+        let recording = client_frames(nonces, &[b"login"]).await;
+        client.write_all(&recording).await.unwrap();
+        let mut received = [0u8; 5];
+        relay.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"login");
+
+        // Play it back to a new relay session.
+        let (mut attacker, relay) = duplex(1024);
+        attacker.write_all(&hello).await.unwrap();
+        let mut relay = Stream::accept(relay, &keys).await.unwrap();
+        attacker.write_all(&recording).await.unwrap();
+        let e = relay.read_exact(&mut received).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn oversized_payload_is_split() {
+        let nonces = Nonces { client: 1, relay: 2 };
+        let payload = vec![5u8; MAX_PAYLOAD + 10];
+        let frames = client_frames(nonces, &[&payload]).await;
+
+        let first = u32::from_le_bytes(frames[..LENGTH_PREFIX].try_into().unwrap()) as usize;
+        assert_eq!(first, MAX_PAYLOAD);
+        let second_start = LENGTH_PREFIX + MAX_PAYLOAD + TAG_LENGTH;
+        let second = u32::from_le_bytes(frames[second_start..second_start + LENGTH_PREFIX].try_into().unwrap());
+        assert_eq!(second, 10);
+
+        let (mut client, relay) = duplex(2 * frames.len());
+        client.write_all(&frames).await.unwrap();
+        let mut relay = Stream::keyed(relay, KEY, nonces, false);
+        let mut received = vec![0u8; payload.len()];
+        relay.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, payload);
+    }
+}
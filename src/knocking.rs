@@ -0,0 +1,192 @@
+//! Port knocking in front of a listener.
+//!
+//! A listener with knock ports only accepts clients whose IP has connected
+//! to each knock port in order within `window_secs`. Knock connections are
+//! closed as soon as they are accepted, and a wrong port starts the sequence
+//! over. Anyone who hasn't knocked sees the listener close on them, which is
+//! enough to keep scanners and strangers off a private test relay.
+//!
+//! Knocks are seen by accepting on ordinary TCP listeners bound for the
+//! lifetime of the process, not by watching raw SYNs, so it works without
+//! privileges but the knock ports show up as open to any scanner. The gate
+//! is only as strong as the secrecy of the sequence, and a sequence in
+//! ascending port order is completed by any sequential scan; `--check`
+//! warns about those.
+
+use crate::config::{Config, KnockingConfig};
+use crate::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+#[derive(Default)]
+pub struct Knocker {
+    state: Mutex<KnockState>,
+}
+
+#[derive(Default)]
+struct KnockState {
+    /// Next knock expected from an IP and when its sequence started.
+    progress: HashMap<IpAddr, (usize, Instant)>,
+    /// IPs that completed the sequence, until when they may connect.
+    open: HashMap<IpAddr, Instant>,
+}
+
+impl Knocker {
+    /// Records a knock from `peer` on the port at `index` in the sequence.
+    /// Returns whether it completed the sequence.
+    pub fn knock(&self, peer: IpAddr, index: usize, config: &KnockingConfig) -> bool {
+        self.knock_at(peer, index, config, Instant::now())
+    }
+
+    fn knock_at(&self, peer: IpAddr, index: usize, config: &KnockingConfig, now: Instant) -> bool {
+        let window = Duration::from_secs(config.window_secs);
+        let mut state = self.state.lock().unwrap();
+        state.progress.retain(|_, (_, started)| now.duration_since(*started) <= window);
+        state.open.retain(|_, until| *until > now);
+
+        let expected = state.progress.get(&peer).map_or(0, |(next, _)| *next);
+        if index != expected && index != 0 {
+            state.progress.remove(&peer);
+            return false;
+        }
+        // A first knock always (re)starts the sequence.
+        if index == 0 {
+            state.progress.insert(peer, (0, now));
+        }
+
+        if index + 1 == config.ports.len() {
+            state.progress.remove(&peer);
+            state.open.insert(peer, now + Duration::from_secs(config.open_secs));
+            return true;
+        }
+        if let Some((next, _)) = state.progress.get_mut(&peer) {
+            *next = index + 1;
+        }
+        false
+    }
+
+    pub fn is_open(&self, peer: IpAddr) -> bool {
+        self.is_open_at(peer, Instant::now())
+    }
+
+    fn is_open_at(&self, peer: IpAddr, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.open.get(&peer).is_some_and(|until| *until > now)
+    }
+}
+
+/// Binds the knock ports of the listener on `listen`, in sequence order.
+pub async fn bind(config: &Config, listen: &str) -> io::Result<Vec<TcpListener>> {
+    let Some(route) = config.listener(listen) else {
+        return Ok(Vec::new());
+    };
+    let mut listeners = Vec::new();
+    for address in route.knock_addresses(config) {
+        listeners.push(TcpListener::bind(&address).await?);
+    }
+    Ok(listeners)
+}
+
+/// Accepts knocks on the port at `index` of the sequence of `listen`.
+pub async fn run(
+    knock: TcpListener,
+    index: usize,
+    listen: String,
+    config: watch::Receiver<Arc<Config>>,
+    knocker: Arc<Knocker>,
+) {
+    while let Ok((stream, peer)) = knock.accept().await {
+        drop(stream);
+        let current = Arc::clone(&config.borrow());
+        let Some(route) = current.listener(&listen) else {
+            continue;
+        };
+        if knocker.knock(peer.ip(), index, route.knocking(&current)) {
+            debug!("[{}] {} knocked, accepting it for {}s", route.name(), peer.ip(), route.knocking(&current).open_secs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn config(ports: &[u16]) -> KnockingConfig {
+        KnockingConfig {
+            ports: ports.to_vec(),
+            window_secs: 10,
+            open_secs: 60,
+        }
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn sequence_in_order_opens() {
+        let (knocker, config, start) = (Knocker::default(), config(&[1, 2, 3]), Instant::now());
+        assert!(!knocker.knock_at(PEER, 0, &config, start));
+        assert!(!knocker.knock_at(PEER, 1, &config, start + secs(1)));
+        assert!(!knocker.is_open_at(PEER, start + secs(1)));
+        assert!(knocker.knock_at(PEER, 2, &config, start + secs(2)));
+        assert!(knocker.is_open_at(PEER, start + secs(2)));
+    }
+
+    #[test]
+    fn wrong_port_resets_progress() {
+        let (knocker, config, start) = (Knocker::default(), config(&[1, 2, 3]), Instant::now());
+        knocker.knock_at(PEER, 0, &config, start);
+        knocker.knock_at(PEER, 1, &config, start);
+        assert!(!knocker.knock_at(PEER, 1, &config, start));
+        assert!(!knocker.knock_at(PEER, 2, &config, start));
+        assert!(!knocker.is_open_at(PEER, start));
+    }
+
+    #[test]
+    fn repeated_first_knock_restarts() {
+        let (knocker, config, start) = (Knocker::default(), config(&[1, 2, 3]), Instant::now());
+        knocker.knock_at(PEER, 0, &config, start);
+        knocker.knock_at(PEER, 1, &config, start);
+        knocker.knock_at(PEER, 0, &config, start + secs(8));
+        assert!(!knocker.knock_at(PEER, 2, &config, start + secs(8)));
+
+        // The window now counts from the restart.
+        knocker.knock_at(PEER, 0, &config, start + secs(8));
+        knocker.knock_at(PEER, 1, &config, start + secs(15));
+        assert!(knocker.knock_at(PEER, 2, &config, start + secs(16)));
+    }
+
+    #[test]
+    fn knock_after_the_window_is_ignored() {
+        let (knocker, config, start) = (Knocker::default(), config(&[1, 2, 3]), Instant::now());
+        knocker.knock_at(PEER, 0, &config, start);
+        knocker.knock_at(PEER, 1, &config, start + secs(5));
+        assert!(!knocker.knock_at(PEER, 2, &config, start + secs(11)));
+        assert!(!knocker.is_open_at(PEER, start + secs(11)));
+    }
+
+    #[test]
+    fn closes_after_open_secs() {
+        let (knocker, config, start) = (Knocker::default(), config(&[1, 2]), Instant::now());
+        knocker.knock_at(PEER, 0, &config, start);
+        assert!(knocker.knock_at(PEER, 1, &config, start));
+        assert!(knocker.is_open_at(PEER, start + secs(59)));
+        assert!(!knocker.is_open_at(PEER, start + secs(60)));
+    }
+
+    #[test]
+    fn single_port_sequence_opens() {
+        let (knocker, config, start) = (Knocker::default(), config(&[1]), Instant::now());
+        assert!(knocker.knock_at(PEER, 0, &config, start));
+        assert!(knocker.is_open_at(PEER, start));
+    }
+}
@@ -1,49 +1,379 @@
-use futures::StreamExt;
-use std::convert::TryInto;
+mod advertisement;
+mod banner;
+mod buffer_pool;
+mod check;
+mod checksum;
+mod cli;
+#[cfg(feature = "tunnel")]
+mod client;
+mod compression;
+mod config;
+mod descriptors;
+mod discovery;
+mod dry_run;
+mod features;
+mod framing;
+mod generate_config;
+#[cfg(feature = "tunnel")]
+mod integrity;
+mod knocking;
+mod logging;
+#[cfg(feature = "tunnel")]
+mod obfuscation;
+mod pacing;
+mod probe;
+#[cfg(unix)]
+mod reload;
+mod routing;
+mod sequence;
+mod waiting_room;
+
+use clap::Parser;
+use cli::{Cli, Command};
+#[cfg(feature = "tunnel")]
+use config::ClientConfig;
+use config::{Config, ListenerConfig};
+use descriptors::Descriptors;
+use discovery::Discovery;
+use framing::PacketCodec;
+use knocking::Knocker;
+use pacing::Pacer;
+use sequence::Sequencer;
+use waiting_room::WaitingRoom;
+use bytes::buf::UninitSlice;
+use bytes::{Buf, BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
 use std::error::Error;
 use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use tokio::io::{self, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio_util::codec::{BytesCodec, FramedRead};
+use tokio::sync::{mpsc, watch};
+use tokio_util::codec::{BytesCodec, FramedRead, FramedWrite};
 
-const NETWORKMESSAGE_MAXSIZE: usize = 65500;
 const INITIAL_BUFFER_POSITION: usize = 8;
-const MAX_BODY_LENGTH: usize = NETWORKMESSAGE_MAXSIZE - 2 - 4 - 8;
+const LENGTH_HEADER_SIZE: usize = 2;
+const CHECKSUM_SIZE: usize = 4;
+// Bytes do buffer que não podem ser usados pelo corpo: tamanho (2), checksum (4) e cabeçalho (8)
+const BODY_OVERHEAD: usize = 2 + 4 + 8;
+
+/// Generates `add_*`/`get_*`/`peek_*` methods for integer types. Values are converted
+/// in the message's byte order with `to_le_bytes`/`from_le_bytes` (or the
+/// `be` versions) rather than through pointers, so the wire format doesn't
+/// depend on the host.
+macro_rules! int_accessors {
+    ($($add:ident, $get:ident, $peek:ident: $ty:ty;)*) => {
+        $(
+            pub fn $add(&mut self, value: $ty) -> Result<(), NetworkMessageError> {
+                let bytes = match self.byte_order {
+                    ByteOrder::Little => value.to_le_bytes(),
+                    ByteOrder::Big => value.to_be_bytes(),
+                };
+                if !self.can_add(bytes.len()) {
+                    return Err(NetworkMessageError::SizeError);
+                }
 
+                self.write(&bytes);
+                Ok(())
+            }
+
+            pub fn $get(&mut self) -> Result<$ty, NetworkMessageError> {
+                let value = self.$peek();
+                match value {
+                    Ok(_) => self.position += std::mem::size_of::<$ty>(),
+                    Err(_) => self.overrun = true,
+                }
+                value
+            }
+
+            /// Reads the value without moving past it.
+            pub fn $peek(&self) -> Result<$ty, NetworkMessageError> {
+                let bytes = self.bytes_at(self.position)?;
+                Ok(match self.byte_order {
+                    ByteOrder::Little => <$ty>::from_le_bytes(bytes),
+                    ByteOrder::Big => <$ty>::from_be_bytes(bytes),
+                })
+            }
+        )*
+    };
+}
+
+/// Byte order of the integers read and written by the typed accessors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ByteOrder {
+    /// What the game protocol uses.
+    #[default]
+    Little,
+    Big,
+}
+
+/// A packet being built or parsed.
+///
+/// Built messages reserve `INITIAL_BUFFER_POSITION` bytes for the header in
+/// front of the body and get their buffer from `buffer_pool`, which it goes
+/// back to when the message is dropped. Messages made with `from_bytes` parse
+/// the received bytes in place, header included, and hand them back with
+/// `into_bytes`.
 pub struct NetworkMessage {
-    buffer: Vec<u8>,
+    buffer: BytesMut,
+    /// Whether `buffer` came from `buffer_pool` and should be returned to it.
+    pooled: bool,
+    /// Where the body starts in `buffer`.
+    start: usize,
     position: usize,
     length: usize,
+    max_size: usize,
     overrun: bool,
+    byte_order: ByteOrder,
+}
+
+impl Default for NetworkMessage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkMessage {
     pub fn new() -> Self {
+        Self::with_max_size(config::DEFAULT_MESSAGE_MAX_SIZE)
+    }
+
+    pub fn with_max_size(max_size: usize) -> Self {
+        let mut buffer = buffer_pool::take(max_size);
+        buffer.resize(INITIAL_BUFFER_POSITION, 0);
         NetworkMessage {
-            buffer: vec![0; NETWORKMESSAGE_MAXSIZE],
+            buffer,
+            pooled: true,
+            start: INITIAL_BUFFER_POSITION,
             position: INITIAL_BUFFER_POSITION,
             length: 0,
+            max_size,
             overrun: false,
+            byte_order: ByteOrder::default(),
+        }
+    }
+
+    /// Parses `bytes` as received, without copying them.
+    pub fn from_bytes(bytes: BytesMut) -> Self {
+        NetworkMessage {
+            start: 0,
+            position: 0,
+            length: bytes.len(),
+            max_size: bytes.len() + BODY_OVERHEAD,
+            buffer: bytes,
+            pooled: false,
+            overrun: false,
+            byte_order: ByteOrder::default(),
+        }
+    }
+
+    /// The underlying bytes, for forwarding a parsed message untouched. A
+    /// pooled buffer handed out this way is not returned to the pool.
+    pub fn into_bytes(mut self) -> BytesMut {
+        std::mem::take(&mut self.buffer)
+    }
+
+    pub fn byte_order(&self) -> ByteOrder {
+        self.byte_order
+    }
+
+    /// Changes the byte order of the typed accessors from here on. The length
+    /// header is always little-endian.
+    pub fn set_byte_order(&mut self, byte_order: ByteOrder) {
+        self.byte_order = byte_order;
+    }
+
+    /// Offset in the buffer the next read or write happens at. The body of a
+    /// built message starts at `INITIAL_BUFFER_POSITION`, after the reserved
+    /// header, and that of a parsed one at 0.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Moves to `position`, which must lie between the start of the body and
+    /// the end of the data.
+    pub fn set_position(&mut self, position: usize) -> Result<(), NetworkMessageError> {
+        if position < self.start || position > self.end() {
+            return Err(NetworkMessageError::ReadError);
         }
+        self.position = position;
+        Ok(())
+    }
+
+    /// Moves past `count` bytes without reading them.
+    pub fn skip(&mut self, count: usize) -> Result<(), NetworkMessageError> {
+        if count > self.remaining() {
+            self.overrun = true;
+            return Err(NetworkMessageError::ReadError);
+        }
+        self.position += count;
+        Ok(())
+    }
+
+    /// Bytes left to read after the current position.
+    pub fn remaining(&self) -> usize {
+        self.end().saturating_sub(self.position)
+    }
+
+    fn end(&self) -> usize {
+        self.length + self.start
+    }
+
+    /// Writes the length header into the space reserved in front of the body
+    /// and returns the packet, header included, ready to be sent. Parsed
+    /// messages already carry their header and are returned as they are.
+    pub fn finalize(&mut self) -> Result<&[u8], NetworkMessageError> {
+        if self.start < LENGTH_HEADER_SIZE {
+            return Ok(&self.buffer[..self.end()]);
+        }
+        let Ok(length) = u16::try_from(self.length) else {
+            return Err(NetworkMessageError::SizeError);
+        };
+        let header = self.start - LENGTH_HEADER_SIZE;
+        self.buffer[header..self.start].copy_from_slice(&length.to_le_bytes());
+        Ok(&self.buffer[header..self.end()])
+    }
+
+    /// Offset of the Adler-32 checksum: the 4 bytes in front of the body of a
+    /// built message, or those after the length header of a parsed one.
+    fn checksum_offset(&self) -> usize {
+        if self.start >= LENGTH_HEADER_SIZE + CHECKSUM_SIZE {
+            self.start - CHECKSUM_SIZE
+        } else {
+            LENGTH_HEADER_SIZE
+        }
+    }
+
+    /// Adler-32 of the body, the bytes after the checksum.
+    pub fn compute_checksum(&self) -> Result<u32, NetworkMessageError> {
+        let body = self.checksum_offset() + CHECKSUM_SIZE;
+        if body > self.end() {
+            return Err(NetworkMessageError::ReadError);
+        }
+        Ok(checksum::adler32(&self.buffer[body..self.end()]))
+    }
+
+    /// Whether the checksum in the message matches its body. Messages too
+    /// short to hold a checksum never match.
+    pub fn verify_checksum(&self) -> bool {
+        let offset = self.checksum_offset();
+        let Ok(expected) = self.compute_checksum() else {
+            return false;
+        };
+        self.buffer[offset..offset + CHECKSUM_SIZE] == expected.to_le_bytes()
+    }
+
+    /// Rewrites the checksum after the body has been modified.
+    pub fn update_checksum(&mut self) -> Result<(), NetworkMessageError> {
+        let checksum = self.compute_checksum()?;
+        let offset = self.checksum_offset();
+        self.buffer[offset..offset + CHECKSUM_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        Ok(())
+    }
+
+    /// Like `finalize`, with the body's checksum between the length header and
+    /// the body; the length then counts the checksum too. Parsed messages get
+    /// their checksum updated and are returned as they are.
+    pub fn finalize_with_checksum(&mut self) -> Result<&[u8], NetworkMessageError> {
+        self.update_checksum()?;
+        self.finalize_with_prefix()
+    }
+
+    /// The sequence number newer protocol versions send in place of the
+    /// checksum.
+    pub fn sequence(&self) -> Result<u32, NetworkMessageError> {
+        Ok(u32::from_le_bytes(self.bytes_at(self.checksum_offset())?))
     }
 
+    pub fn set_sequence(&mut self, sequence: u32) -> Result<(), NetworkMessageError> {
+        let offset = self.checksum_offset();
+        if offset + CHECKSUM_SIZE > self.end() {
+            return Err(NetworkMessageError::SizeError);
+        }
+        self.buffer[offset..offset + CHECKSUM_SIZE].copy_from_slice(&sequence.to_le_bytes());
+        Ok(())
+    }
+
+    /// Like `finalize_with_checksum`, with the next number of `sequencer`
+    /// instead of the checksum, for packets the proxy sends on its own.
+    pub fn finalize_with_sequence(&mut self, sequencer: &mut Sequencer) -> Result<&[u8], NetworkMessageError> {
+        self.set_sequence(sequencer.inject())?;
+        self.finalize_with_prefix()
+    }
+
+    /// Writes a length header counting the checksum or sequence number in
+    /// front of the body of a built message.
+    fn finalize_with_prefix(&mut self) -> Result<&[u8], NetworkMessageError> {
+        if self.start < LENGTH_HEADER_SIZE + CHECKSUM_SIZE {
+            return Ok(&self.buffer[..self.end()]);
+        }
+        let Ok(length) = u16::try_from(self.length + CHECKSUM_SIZE) else {
+            return Err(NetworkMessageError::SizeError);
+        };
+        let header = self.checksum_offset() - LENGTH_HEADER_SIZE;
+        self.buffer[header..header + LENGTH_HEADER_SIZE].copy_from_slice(&length.to_le_bytes());
+        Ok(&self.buffer[header..self.end()])
+    }
+
+    /// The message as a multi-line hex and ASCII dump, see `Display`.
+    pub fn hexdump(&self) -> String {
+        self.to_string()
+    }
+
+    fn max_body_length(&self) -> usize {
+        self.max_size.saturating_sub(BODY_OVERHEAD)
+    }
+
+    /// Position writes may reach. Built messages keep the last byte below
+    /// the limit free; parsed ones can be overwritten up to their end but
+    /// not grown.
+    fn write_limit(&self) -> usize {
+        if self.start < LENGTH_HEADER_SIZE {
+            self.end()
+        } else {
+            self.max_body_length().saturating_sub(1)
+        }
+    }
+
+    /// Appends `bytes` at the current position, growing the buffer as needed.
+    /// Callers check `can_add` first.
+    fn write(&mut self, bytes: &[u8]) {
+        let end = self.position + bytes.len();
+        if self.buffer.len() < end {
+            self.buffer.resize(end, 0);
+        }
+        self.buffer[self.position..end].copy_from_slice(bytes);
+        self.position = end;
+        // Writing after a rewind overwrites bytes already counted.
+        self.length = self.length.max(end - self.start);
+    }
+
+    /// Reads the length header of a parsed message and limits the message to
+    /// the packet it announces. Returns the body length, or 0 when the header
+    /// is missing, doesn't fit the received bytes or the message was built
+    /// rather than parsed.
     pub fn decode_header(&mut self) -> i32 {
-        if self.length < 2 {
-            println!("Not enough data to decode header");
+        if self.start >= LENGTH_HEADER_SIZE {
+            debug!("Built messages have no header to decode");
+            return 0;
+        }
+        if self.length < LENGTH_HEADER_SIZE {
+            debug!("Not enough data to decode header");
             return 0;
         }
 
-        let new_size = (self.buffer[0] as i32) | ((self.buffer[1] as i32) << 8);
+        let new_size = usize::from(u16::from_le_bytes([self.buffer[0], self.buffer[1]]));
 
-        if new_size < 0 || new_size as usize > NETWORKMESSAGE_MAXSIZE {
-            println!("Invalid decoded header length: {}", new_size);
+        if new_size > self.max_size || LENGTH_HEADER_SIZE + new_size > self.buffer.len() {
+            debug!("Invalid decoded header length: {}", new_size);
             return 0;
         }
 
-        self.length = new_size as usize;
-        println!("Decoded header length: {}", self.length);
-        self.length as i32
+        // The length of a parsed message counts its header.
+        self.length = LENGTH_HEADER_SIZE + new_size;
+        debug!("Decoded header length: {}", new_size);
+        new_size as i32
     }
 
     pub fn add_bytes(&mut self, bytes: &[u8]) -> Result<(), NetworkMessageError> {
@@ -58,63 +388,115 @@ impl NetworkMessage {
             );
             return Err(NetworkMessageError::SizeError);
         }
-        if bytes.len() > NETWORKMESSAGE_MAXSIZE {
+        if bytes.len() > self.max_size {
             eprintln!(
                 "[NetworkMessage::add_bytes] - Exceeded NetworkMessage max size: {}, actual size: {}",
-                NETWORKMESSAGE_MAXSIZE, bytes.len()
+                self.max_size, bytes.len()
             );
             return Err(NetworkMessageError::SizeError);
         }
 
-        if self.buffer.len() < self.position + bytes.len() {
-            self.buffer.resize(self.position + bytes.len(), 0);
-        }
-
-        self.buffer[self.position..self.position + bytes.len()].copy_from_slice(bytes);
-        self.position += bytes.len();
-        self.length += bytes.len();
+        self.write(bytes);
         Ok(())
     }
 
-    fn can_read(&self, size: usize) -> bool {
-        if (self.position + size) > (self.length + INITIAL_BUFFER_POSITION) || size >= (NETWORKMESSAGE_MAXSIZE - self.position) {
-            return false;
+    fn can_read_at(&self, position: usize, size: usize) -> bool {
+        position.checked_add(size).is_some_and(|end| end <= self.end())
+    }
+
+    fn bytes_at<const N: usize>(&self, position: usize) -> Result<[u8; N], NetworkMessageError> {
+        if !self.can_read_at(position, N) {
+            return Err(NetworkMessageError::ReadError);
         }
-        true
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(&self.buffer[position..position + N]);
+        Ok(bytes)
     }
 
     pub fn get_string(&mut self, string_len: Option<usize>) -> Result<String, NetworkMessageError> {
+        match self.string_at(self.position, string_len) {
+            Ok((string, end)) => {
+                self.position = end;
+                Ok(string)
+            }
+            Err(e) => {
+                if let NetworkMessageError::ReadError = e {
+                    self.overrun = true;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Writes `string` with the u16 length prefix `get_string(None)` reads.
+    pub fn add_string(&mut self, string: &str) -> Result<(), NetworkMessageError> {
+        let Ok(length) = u16::try_from(string.len()) else {
+            eprintln!(
+                "[NetworkMessage::add_string] - String of {} bytes doesn't fit a u16 length",
+                string.len()
+            );
+            return Err(NetworkMessageError::SizeError);
+        };
+        if !self.can_add(2 + string.len()) {
+            return Err(NetworkMessageError::SizeError);
+        }
+
+        self.add_u16(length)?;
+        self.write(string.as_bytes());
+        Ok(())
+    }
+
+    /// Reads a string like `get_string` without moving past it.
+    pub fn peek_string(&self, string_len: Option<usize>) -> Result<String, NetworkMessageError> {
+        self.string_at(self.position, string_len).map(|(string, _)| string)
+    }
+
+    /// The string starting at `position` and the position right after it.
+    fn string_at(&self, mut position: usize, string_len: Option<usize>) -> Result<(String, usize), NetworkMessageError> {
         let string_len = match string_len {
             Some(len) => len,
             None => {
-                let len = self.get::<u16>() as usize;
-                println!("Comprimento da string lido: {}", len);
+                let bytes = self.bytes_at(position)?;
+                position += bytes.len();
+                let len = match self.byte_order {
+                    ByteOrder::Little => u16::from_le_bytes(bytes),
+                    ByteOrder::Big => u16::from_be_bytes(bytes),
+                } as usize;
+                debug!("Comprimento da string lido: {}", len);
                 len
             }
         };
 
         if string_len == 0 {
-            println!("O comprimento da string é 0, retornando string vazia.");
-            return Ok(String::new());
+            debug!("O comprimento da string é 0, retornando string vazia.");
+            return Ok((String::new(), position));
         }
 
-        if !self.can_read(string_len) {
-            self.overrun = true;
+        if !self.can_read_at(position, string_len) {
             return Err(NetworkMessageError::ReadError);
         }
 
-        let start = self.position;
-        self.position += string_len;
-
-        match std::str::from_utf8(&self.buffer[start..self.position]) {
-            Ok(s) => Ok(s.to_string()),
+        match std::str::from_utf8(&self.buffer[position..position + string_len]) {
+            Ok(s) => Ok((s.to_string(), position + string_len)),
             Err(e) => {
-                println!("Erro ao decodificar string: {}", e);
+                debug!("Erro ao decodificar string: {}", e);
                 Err(NetworkMessageError::InvalidUtf8)
             }
         }
     }
 
+    int_accessors! {
+        add_u8, get_u8, peek_u8: u8;
+        add_u16, get_u16, peek_u16: u16;
+        add_u32, get_u32, peek_u32: u32;
+        add_u64, get_u64, peek_u64: u64;
+        add_i8, get_i8, peek_i8: i8;
+        add_i16, get_i16, peek_i16: i16;
+        add_i32, get_i32, peek_i32: i32;
+        add_i64, get_i64, peek_i64: i64;
+    }
+
+    #[deprecated(note = "copies the raw bytes of `T`, padding included; use the typed add_u8..add_i64")]
     pub fn add<T: Copy>(&mut self, value: T) -> Result<(), NetworkMessageError> {
         let size = std::mem::size_of::<T>();
 
@@ -126,39 +508,102 @@ impl NetworkMessage {
             std::slice::from_raw_parts(&value as *const T as *const u8, size)
         };
 
-        self.buffer[self.position..self.position + size].copy_from_slice(value_bytes);
-        self.position += size;
-        self.length += size;
-
+        self.write(value_bytes);
         Ok(())
     }
 
-    fn get<T>(&mut self) -> T
-    where
-        T: Copy + Default + Sized,
-    {
-        let size = std::mem::size_of::<T>();
+    fn can_add(&self, size: usize) -> bool {
+        size + self.position <= self.write_limit()
+    }
+}
 
-        if !self.can_read(size) {
-            return T::default(); // Retorna o valor padrão para T se não for possível ler
+impl Drop for NetworkMessage {
+    fn drop(&mut self) {
+        if self.pooled {
+            buffer_pool::give_back(std::mem::take(&mut self.buffer));
         }
+    }
+}
 
-        let mut value: T = T::default();
-        let bytes = &self.buffer[self.position..self.position + size];
-        unsafe {
-            std::ptr::copy_nonoverlapping(
-                bytes.as_ptr(),
-                &mut value as *mut T as *mut u8,
-                size,
-            );
+/// Bytes shown per line of a hex dump.
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Renders the message like `hexdump -C`: the offset in the buffer, the bytes
+/// in hex and the printable ones as ASCII. Offsets match `position()`, so a
+/// built message shows the header space reserved in front of the body.
+impl fmt::Display for NetworkMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let data = &self.buffer[..self.end()];
+        for (line, chunk) in data.chunks(HEXDUMP_WIDTH).enumerate() {
+            write!(f, "{:08x} ", line * HEXDUMP_WIDTH)?;
+            for column in 0..HEXDUMP_WIDTH {
+                if column % 8 == 0 {
+                    write!(f, " ")?;
+                }
+                match chunk.get(column) {
+                    Some(byte) => write!(f, "{:02x} ", byte)?,
+                    None => write!(f, "   ")?,
+                }
+            }
+            write!(f, " |")?;
+            for &byte in chunk {
+                let shown = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+                write!(f, "{}", shown)?;
+            }
+            writeln!(f, "|")?;
         }
+        write!(f, "{:08x}", data.len())
+    }
+}
 
-        self.position += size;
-        value
+/// Reads the unread part of the body, so codecs and parsers working on
+/// `bytes::Buf` can consume a message without copying it out first.
+impl Buf for NetworkMessage {
+    fn remaining(&self) -> usize {
+        NetworkMessage::remaining(self)
     }
 
-    fn can_add(&self, size: usize) -> bool {
-        (size + self.position) < MAX_BODY_LENGTH
+    fn chunk(&self) -> &[u8] {
+        &self.buffer[self.position..self.end()]
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        assert!(
+            cnt <= NetworkMessage::remaining(self),
+            "cannot advance past the end of the NetworkMessage"
+        );
+        self.position += cnt;
+    }
+}
+
+/// Writes at the current position like the `add_*` methods, within the same
+/// size limit.
+unsafe impl BufMut for NetworkMessage {
+    fn remaining_mut(&self) -> usize {
+        self.write_limit().saturating_sub(self.position)
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining_mut(), "cannot write past the NetworkMessage max size");
+        let end = self.position + cnt;
+        if self.buffer.len() < end {
+            // The caller initialised these bytes through chunk_mut, which only
+            // reaches past the buffer's length when writing at its end.
+            self.buffer.set_len(end);
+        }
+        self.position = end;
+        self.length = self.length.max(end - self.start);
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        let room = self.remaining_mut();
+        if self.position < self.buffer.len() {
+            let end = self.buffer.len().min(self.position + room);
+            return UninitSlice::new(&mut self.buffer[self.position..end]);
+        }
+        let chunk = self.buffer.chunk_mut();
+        let len = chunk.len().min(room);
+        &mut chunk[..len]
     }
 }
 
@@ -181,42 +626,226 @@ impl fmt::Display for NetworkMessageError {
 
 impl Error for NetworkMessageError {}
 
-async fn handle_connection(mut inbound: TcpStream, destination: String) -> io::Result<()> {
-    let mut outbound = TcpStream::connect(destination).await?;
-    let (mut inbound_reader, mut inbound_writer) = inbound.split();
-    let (mut outbound_reader, mut outbound_writer) = outbound.split();
-    let (tx, mut rx) = mpsc::channel(32);
+/// A value that can be written to a `NetworkMessage`, in the message's byte
+/// order for integers.
+pub trait Encode {
+    fn encode(&self, message: &mut NetworkMessage) -> Result<(), NetworkMessageError>;
+}
 
-    let inbound_to_outbound = async {
-        let mut framed_read = FramedRead::new(inbound_reader, BytesCodec::new());
+/// A value that can be read from a `NetworkMessage` at its current position.
+pub trait Decode: Sized {
+    fn decode(message: &mut NetworkMessage) -> Result<Self, NetworkMessageError>;
+}
 
-        while let Some(Ok(bytes)) = framed_read.next().await {
-            println!("Client -> Server Captured: {:?}", &bytes);
+/// Implements `Encode` and `Decode` for integer types with the typed accessors.
+macro_rules! int_codecs {
+    ($($ty:ty: $add:ident, $get:ident;)*) => {
+        $(
+            impl Encode for $ty {
+                fn encode(&self, message: &mut NetworkMessage) -> Result<(), NetworkMessageError> {
+                    message.$add(*self)
+                }
+            }
 
-            let mut message = NetworkMessage::new();
-            if let Err(e) = message.add_bytes(&bytes) {
-                eprintln!("Error adding bytes: {}", e);
-                continue;
+            impl Decode for $ty {
+                fn decode(message: &mut NetworkMessage) -> Result<Self, NetworkMessageError> {
+                    message.$get()
+                }
             }
+        )*
+    };
+}
 
-            // Converter os bytes capturados para uma lista de strings hexadecimais
-            let decoded_values: Vec<String> = bytes.iter().map(|&byte| format!("{:#x}", byte)).collect();
-            println!("Decoded to hex: {:?}", decoded_values);
+int_codecs! {
+    u8: add_u8, get_u8;
+    u16: add_u16, get_u16;
+    u32: add_u32, get_u32;
+    u64: add_u64, get_u64;
+    i8: add_i8, get_i8;
+    i16: add_i16, get_i16;
+    i32: add_i32, get_i32;
+    i64: add_i64, get_i64;
+}
+
+/// A single byte, 0 or 1 when written; any non-zero byte reads as true.
+impl Encode for bool {
+    fn encode(&self, message: &mut NetworkMessage) -> Result<(), NetworkMessageError> {
+        message.add_u8(u8::from(*self))
+    }
+}
+
+impl Decode for bool {
+    fn decode(message: &mut NetworkMessage) -> Result<Self, NetworkMessageError> {
+        Ok(message.get_u8()? != 0)
+    }
+}
 
+/// Strings carry a u16 length prefix, like `add_string`/`get_string(None)`.
+impl Encode for str {
+    fn encode(&self, message: &mut NetworkMessage) -> Result<(), NetworkMessageError> {
+        message.add_string(self)
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, message: &mut NetworkMessage) -> Result<(), NetworkMessageError> {
+        message.add_string(self)
+    }
+}
+
+impl Decode for String {
+    fn decode(message: &mut NetworkMessage) -> Result<Self, NetworkMessageError> {
+        message.get_string(None)
+    }
+}
+
+/// Declares a protocol structure and implements `Encode` and `Decode` for it,
+/// reading and writing the fields in declaration order:
+///
+/// ```ignore
+/// message_struct! {
+///     pub struct Position {
+///         pub x: u16,
+///         pub y: u16,
+///         pub z: u8,
+///     }
+/// }
+///
+/// let position = Position::decode(&mut message)?;
+/// ```
+///
+/// Every field type must itself implement `Encode` and `Decode`, so
+/// structures can be nested.
+#[macro_export]
+macro_rules! message_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident: $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::Encode for $name {
+            fn encode(&self, message: &mut $crate::NetworkMessage) -> Result<(), $crate::NetworkMessageError> {
+                $($crate::Encode::encode(&self.$field, message)?;)*
+                Ok(())
+            }
+        }
+
+        impl $crate::Decode for $name {
+            fn decode(message: &mut $crate::NetworkMessage) -> Result<Self, $crate::NetworkMessageError> {
+                Ok($name {
+                    $($field: $crate::Decode::decode(message)?,)*
+                })
+            }
+        }
+    };
+}
+
+async fn handle_connection(
+    mut inbound: TcpStream,
+    config: Arc<Config>,
+    route: ListenerConfig,
+    state: Arc<ListenerState>,
+) -> io::Result<()> {
+    let destination = routing::resolve(&config, &route).map_err(|e| io::Error::new(io::ErrorKind::NotFound, e))?;
+    let waiting_room = route.waiting_room(&config);
+
+    let client_codec = compression::negotiate(&mut inbound, route.compression(&config)).await?;
+    if client_codec.is_compressed() {
+        debug!("[{}] Client negotiated zstd compression", route.name());
+    }
+    #[cfg(feature = "tunnel")]
+    let client = {
+        let client = obfuscation::Stream::accept(inbound, route.obfuscation(&config)).await?;
+        let hmac_keys = route
+            .integrity(&config)
+            .keys()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        integrity::Stream::accept(client, &hmac_keys).await?
+    };
+    #[cfg(not(feature = "tunnel"))]
+    let client = inbound;
+
+    // Held until the session ends so the slot is freed for the next queued client.
+    let _admission = state
+        .waiting_room
+        .enter(waiting_room)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::WouldBlock, e))?;
+
+    state
+        .pacer
+        .wait_turn(route.pacing(&config))
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::WouldBlock, e))?;
+
+    let destination = state.discovery.resolve(&config, destination).await?;
+    let start = state.next_upstream.fetch_add(1, Ordering::Relaxed);
+    let mut outbound = waiting_room::connect_upstream(&destination, start, waiting_room).await?;
+    let session = state.descriptors.sessions.register();
+    let (inbound_reader, mut inbound_writer) = io::split(client);
+    let (outbound_reader, mut outbound_writer) = outbound.split();
+    let (tx, mut rx) = mpsc::channel(config.buffers.channel_capacity);
+    // Sequence numbers sit in the packet header, so they need whole packets.
+    let inbound_codec = if config.inspection.enabled && config.inspection.sequence_numbers {
+        PacketCodec::packets(client_codec.split(), config.buffers.message_max_size)
+    } else {
+        PacketCodec::chunks(client_codec.split())
+    };
+
+    let inbound_to_outbound = async {
+        let mut framed_read = FramedRead::with_capacity(inbound_reader, inbound_codec, config.buffers.read_buffer_size);
+        let mut sequencer = Sequencer::default();
+
+        while let Some(read) = framed_read.next().await {
+            let bytes = match read {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    eprintln!("Error: [{}] reading from the client: {}", route.name(), e);
+                    break;
+                }
+            };
+            session.touch();
+            if !config.inspection.enabled || bytes.len() > config.buffers.message_max_size {
+                tx.send(bytes.freeze()).await.unwrap();
+                continue;
+            }
+
+            let mut message = NetworkMessage::from_bytes(bytes);
+            trace!("Client -> Server Captured:\n{}", message);
+            if config.inspection.sequence_numbers {
+                if let Ok(received) = message.sequence() {
+                    let sequence = sequencer.forward(received).unwrap_or_else(|e| {
+                        debug!("[{}] Client packet out of sequence: {}", route.name(), e);
+                        e.sequence
+                    });
+                    // Can't fail, the sequence number was just read from there.
+                    let _ = message.set_sequence(sequence);
+                }
+            }
             match message.get_string(None) {  // None indica que o comprimento da string deve ser lido do buffer
-                Ok(s) => println!("String capturada: {}", s),
-                Err(e) => println!("Erro ao capturar a string: {}", e),
+                Ok(s) => debug!("String capturada: {}", s),
+                Err(e) => debug!("Erro ao capturar a string: {}", e),
             }
 
-            tx.send(bytes.to_vec()).await.unwrap();
+            tx.send(message.into_bytes().freeze()).await.unwrap();
         }
+        // Closes the channel so send_task finishes once the client is gone.
+        drop(tx);
     };
 
     let outbound_to_inbound = async {
-        let mut framed_read = FramedRead::new(outbound_reader, BytesCodec::new());
+        let mut framed_read = FramedRead::with_capacity(outbound_reader, BytesCodec::new(), config.buffers.read_buffer_size);
+        let mut framed_write = FramedWrite::new(&mut inbound_writer, client_codec);
 
         while let Some(Ok(bytes)) = framed_read.next().await {
-            inbound_writer.write_all(&bytes).await.unwrap();
+            session.touch();
+            framed_write.send(bytes.freeze()).await.unwrap();
         }
     };
 
@@ -224,28 +853,304 @@ async fn handle_connection(mut inbound: TcpStream, destination: String) -> io::R
         while let Some(buffer) = rx.recv().await {
             outbound_writer.write_all(&buffer).await.unwrap();
         }
+        // Forward the client's EOF so the server closes its side and the session ends.
+        let _ = outbound_writer.shutdown().await;
     };
 
-    tokio::join!(inbound_to_outbound, outbound_to_inbound, send_task);
+    tokio::select! {
+        _ = async { tokio::join!(inbound_to_outbound, outbound_to_inbound, send_task) } => {}
+        _ = session.shed() => debug!("[{}] Closed idle session to free file descriptors", route.name()),
+    }
 
     Ok(())
 }
 
+/// State kept by a listener across its connections.
+#[derive(Default)]
+struct ListenerState {
+    /// Shared with the other listeners.
+    descriptors: Arc<Descriptors>,
+    knocker: Arc<Knocker>,
+    discovery: Discovery,
+    waiting_room: WaitingRoom,
+    pacer: Pacer,
+    /// Rotates the first address tried in the listener's upstream group.
+    next_upstream: AtomicUsize,
+}
+
+/// Accepts clients on one listener for as long as it stays configured.
+///
+/// The listener's settings are looked up in the latest configuration on every
+/// accept, so routing and pacing changes from a reload apply to new clients.
+async fn serve(listener: TcpListener, listen: String, config: watch::Receiver<Arc<Config>>, state: Arc<ListenerState>) {
+    loop {
+        let accepted = listener.accept().await;
+        let current = Arc::clone(&config.borrow());
+        let (inbound, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) if descriptors::is_exhausted(&e) => {
+                state.descriptors.recover(&listen, &listener, &e, &current.descriptors).await;
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Error: [{}] failed to accept a connection: {}", listen, e);
+                continue;
+            }
+        };
+        let Some(route) = current.listener(&listen) else {
+            eprintln!("Error: listener {} is no longer configured, dropping {}", listen, peer);
+            continue;
+        };
+        if route.knocking(&current).enabled() && !state.knocker.is_open(peer.ip()) {
+            debug!("[{}] Dropping {} which hasn't knocked", route.name(), peer);
+            continue;
+        }
+        let state = Arc::clone(&state);
+        debug!("[{}] Accepted connection from {}", route.name(), peer);
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(inbound, current, route, state).await {
+                eprintln!("Error: connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:7172").await?;
+    let cli = Cli::parse();
+    if cli.features {
+        features::report();
+        return Ok(());
+    }
+    if let Some(Command::GenerateConfig(args)) = &cli.command {
+        if let Err(e) = generate_config::run(args.output.as_deref(), args.force).await {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    #[cfg(feature = "tunnel")]
+    if let Some(Command::Client(args)) = &cli.command {
+        // Players only need to see problems unless they ask for more.
+        logging::set_level(cli.log_level().unwrap_or(logging::LogLevel::Error));
+        match ClientConfig::from_args(args) {
+            Ok(config) => return client::run(config).await,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let config = match Config::from_cli(&cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::set_level(config.logging.level);
 
-    println!("Listening on 127.0.0.1:7172");
+    if cli.check {
+        let ok = check::run(&config).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if cli.dry_run {
+        dry_run::run(&config).await;
+        return Ok(());
+    }
+
+    // Bind everything up front so a bad address fails startup instead of one listener.
+    let mut listeners = Vec::new();
+    for route in config.listeners() {
+        let listener = TcpListener::bind(&route.listen).await?;
+        let knocks = knocking::bind(&config, &route.listen).await?;
+        listeners.push((listener, knocks, route.listen));
+    }
+    banner::log(&config);
 
-    while let Ok((inbound, _)) = listener.accept().await {
-        let destination = "127.0.0.1:7173".to_string();
+    let advertisement = config.advertisement.clone();
+    let (config_tx, config_rx) = watch::channel(Arc::new(config));
 
+    let probes = probe::ProbeResults::default();
+    tokio::spawn(probe::run(config_rx.clone(), probes.clone()));
+
+    if let Some(advertisement) = advertisement {
+        let shared = config_rx.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(inbound, destination).await {
-                eprintln!("Error: {}", e);
+            if let Err(e) = advertisement::run(advertisement, shared, probes).await {
+                eprintln!("Error: advertisement listener stopped: {}", e);
             }
         });
     }
 
+    #[cfg(unix)]
+    tokio::spawn(reload::run(cli, config_tx));
+    #[cfg(not(unix))]
+    let _config_tx = config_tx;
+
+    let descriptors = Arc::new(Descriptors::default());
+    descriptors.reserve();
+    let servers = listeners.into_iter().map(|(listener, knocks, listen)| {
+        let state = Arc::new(ListenerState {
+            descriptors: Arc::clone(&descriptors),
+            ..ListenerState::default()
+        });
+        for (index, knock) in knocks.into_iter().enumerate() {
+            let knocker = Arc::clone(&state.knocker);
+            tokio::spawn(knocking::run(knock, index, listen.clone(), config_rx.clone(), knocker));
+        }
+        serve(listener, listen, config_rx.clone(), state)
+    });
+    futures::future::join_all(servers).await;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewind_then_write_overwrites() {
+        let mut message = NetworkMessage::new();
+        message.add_u16(1).unwrap();
+        message.add_u16(2).unwrap();
+        message.set_position(INITIAL_BUFFER_POSITION).unwrap();
+        message.add_u16(9).unwrap();
+
+        assert_eq!(message.length, 4);
+        assert_eq!(message.finalize().unwrap(), &[4, 0, 9, 0, 2, 0]);
+    }
+
+    #[test]
+    fn rewind_then_write_past_the_end_grows() {
+        let mut message = NetworkMessage::new();
+        message.add_u16(1).unwrap();
+        message.set_position(INITIAL_BUFFER_POSITION + 1).unwrap();
+        message.add_u32(0x0403_0201).unwrap();
+
+        assert_eq!(message.length, 5);
+        assert_eq!(message.finalize().unwrap(), &[5, 0, 1, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn buf_round_trip() {
+        let mut message = NetworkMessage::new();
+        message.put_u16_le(3);
+        message.put_slice(b"abc");
+        message.put_u32_le(7);
+        message.set_position(INITIAL_BUFFER_POSITION).unwrap();
+
+        assert_eq!(Buf::remaining(&message), 9);
+        assert_eq!(Buf::get_u16_le(&mut message), 3);
+        let mut text = [0; 3];
+        message.copy_to_slice(&mut text);
+        assert_eq!(&text, b"abc");
+        assert_eq!(Buf::get_u32_le(&mut message), 7);
+        assert!(!message.has_remaining());
+    }
+
+    #[test]
+    fn buf_mut_overwrites_after_a_rewind() {
+        let mut message = NetworkMessage::new();
+        message.add_u32(1).unwrap();
+        message.set_position(INITIAL_BUFFER_POSITION).unwrap();
+        message.put_slice(&[9; 8]);
+
+        assert_eq!(message.length, 8);
+        assert_eq!(message.finalize().unwrap()[2..], [9; 8]);
+        assert!(message.to_string().starts_with("00000000"));
+    }
+
+    #[test]
+    fn checksum_round_trip_on_a_built_message() {
+        let mut message = NetworkMessage::new();
+        message.add_string("hello").unwrap();
+        let bytes = BytesMut::from(message.finalize_with_checksum().unwrap());
+
+        let mut parsed = NetworkMessage::from_bytes(bytes);
+        assert!(parsed.verify_checksum());
+        assert_eq!(parsed.compute_checksum().unwrap(), checksum::adler32(&[5, 0, b'h', b'e', b'l', b'l', b'o']));
+
+        // A parsed message that was modified gets its checksum rewritten.
+        parsed.set_position(LENGTH_HEADER_SIZE + CHECKSUM_SIZE + 2).unwrap();
+        parsed.add_u8(b'j').unwrap();
+        assert!(!parsed.verify_checksum());
+        let bytes = BytesMut::from(parsed.finalize_with_checksum().unwrap());
+        assert!(NetworkMessage::from_bytes(bytes).verify_checksum());
+    }
+
+    message_struct! {
+        #[derive(Debug, PartialEq)]
+        struct Position {
+            x: u16,
+            y: u16,
+            z: u8,
+        }
+    }
+
+    message_struct! {
+        #[derive(Debug, PartialEq)]
+        struct Creature {
+            id: u32,
+            health: i8,
+            name: String,
+            visible: bool,
+            position: Position,
+            experience: u64,
+        }
+    }
+
+    #[test]
+    fn message_struct_round_trip() {
+        let creature = Creature {
+            id: 0x1000_0001,
+            health: -1,
+            name: "Rat".to_string(),
+            visible: true,
+            position: Position { x: 32000, y: 31000, z: 7 },
+            experience: 1 << 40,
+        };
+        let mut message = NetworkMessage::new();
+        creature.encode(&mut message).unwrap();
+        assert_eq!(message.length, 4 + 1 + 2 + 3 + 1 + 5 + 8);
+
+        message.set_position(INITIAL_BUFFER_POSITION).unwrap();
+        assert_eq!(Creature::decode(&mut message).unwrap(), creature);
+    }
+
+    #[test]
+    fn parsed_message_last_field_can_be_rewritten() {
+        let mut message = NetworkMessage::from_bytes(BytesMut::from(&[3, 0, 1, 2, 3][..]));
+        message.set_position(4).unwrap();
+        message.add_u8(9).unwrap();
+        message.set_position(3).unwrap();
+        message.add_u16(0x0807).unwrap();
+        assert_eq!(&message.into_bytes()[..], [3, 0, 1, 7, 8]);
+    }
+
+    #[test]
+    fn parsed_message_does_not_grow() {
+        let mut message = NetworkMessage::from_bytes(BytesMut::from(&[3, 0, 1, 2, 3][..]));
+        message.set_position(4).unwrap();
+        assert!(message.add_u16(9).is_err());
+        assert_eq!(BufMut::remaining_mut(&message), 1);
+    }
+
+    #[test]
+    fn decode_header_keeps_the_whole_parsed_packet() {
+        let mut message = NetworkMessage::from_bytes(BytesMut::from(&[3, 0, 1, 2, 3, 4][..]));
+        assert_eq!(message.decode_header(), 3);
+        assert_eq!(message.remaining(), 5);
+        message.set_position(LENGTH_HEADER_SIZE).unwrap();
+        assert_eq!(message.get_u8().unwrap(), 1);
+        assert_eq!(message.get_u16().unwrap(), 0x0302);
+        assert!(message.get_u8().is_err());
+
+        let mut truncated = NetworkMessage::from_bytes(BytesMut::from(&[9, 0, 1][..]));
+        assert_eq!(truncated.decode_header(), 0);
+        assert_eq!(NetworkMessage::new().decode_header(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+//! Optional obfuscation of the client leg.
+//!
+//! Some ISPs throttle traffic they recognise as game traffic. With
+//! obfuscation enabled both ends XOR the byte stream with a ChaCha20
+//! keystream derived from a shared key, so the payload looks random. This is
+//! not encryption in any meaningful sense (there is no authentication), just
+//! enough to stop pattern matching.
+//!
+//! The connecting side opens with an 8 byte random nonce in the clear; each
+//! direction then uses its own ChaCha20 stream selected by that nonce.
+
+use crate::config::ObfuscationConfig;
+use rand::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+pub const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 8;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parses a hex encoded 32 byte key.
+pub fn parse_key(key: &str) -> Result<[u8; KEY_LENGTH], String> {
+    let bytes = hex::decode(key).map_err(|e| format!("obfuscation key is not valid hex: {}", e))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("obfuscation key must be {} bytes ({} hex characters)", KEY_LENGTH, KEY_LENGTH * 2))
+}
+
+struct Keystream {
+    rng: ChaCha20Rng,
+    pending: VecDeque<u8>,
+}
+
+impl Keystream {
+    fn new(key: &[u8; KEY_LENGTH], stream: u64) -> Self {
+        let mut rng = ChaCha20Rng::from_seed(*key);
+        rng.set_stream(stream);
+        Keystream {
+            rng,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn fill(&mut self, len: usize) {
+        let mut block = [0u8; 64];
+        while self.pending.len() < len {
+            self.rng.fill_bytes(&mut block);
+            self.pending.extend(block);
+        }
+    }
+
+    /// XORs `data` with the next keystream bytes and consumes them.
+    fn apply(&mut self, data: &mut [u8]) {
+        let len = data.len();
+        self.fill(len);
+        for (byte, key) in data.iter_mut().zip(self.pending.drain(..len)) {
+            *byte ^= key;
+        }
+    }
+
+    /// XORs `data` into `out` without consuming the keystream, for writes
+    /// that may only be partially accepted.
+    fn peek_apply(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.fill(data.len());
+        out.clear();
+        out.extend(data.iter().zip(self.pending.iter()).map(|(byte, key)| byte ^ key));
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.pending.drain(..len);
+    }
+}
+
+/// A stream that (de)obfuscates everything passing through it, or passes it
+/// through untouched when obfuscation is off.
+pub struct Stream<S> {
+    inner: S,
+    read: Option<Keystream>,
+    write: Option<Keystream>,
+    scratch: Vec<u8>,
+}
+
+impl<S> Stream<S> {
+    pub fn plain(inner: S) -> Self {
+        Stream {
+            inner,
+            read: None,
+            write: None,
+            scratch: Vec::new(),
+        }
+    }
+
+    fn keyed(inner: S, key: &[u8; KEY_LENGTH], nonce: u64, initiator: bool) -> Self {
+        // Each direction gets its own stream so the keystream is never reused.
+        let outgoing = nonce.wrapping_mul(2);
+        let incoming = outgoing.wrapping_add(1);
+        let (write, read) = if initiator { (outgoing, incoming) } else { (incoming, outgoing) };
+        Stream {
+            inner,
+            read: Some(Keystream::new(key, read)),
+            write: Some(Keystream::new(key, write)),
+            scratch: Vec::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream<S> {
+    /// Proxy side: reads the client's nonce when obfuscation is enabled.
+    pub async fn accept(mut inner: S, config: &ObfuscationConfig) -> io::Result<Self> {
+        if !config.enabled {
+            return Ok(Stream::plain(inner));
+        }
+        let key = parse_key(&config.key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let mut nonce = [0u8; NONCE_LENGTH];
+        timeout(HANDSHAKE_TIMEOUT, inner.read_exact(&mut nonce))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no obfuscation nonce received"))??;
+        Ok(Stream::keyed(inner, &key, u64::from_le_bytes(nonce), false))
+    }
+
+    /// Client side: sends a fresh nonce to the relay.
+    pub async fn connect(mut inner: S, key: &[u8; KEY_LENGTH]) -> io::Result<Self> {
+        let nonce = rand::thread_rng().next_u64();
+        inner.write_all(&nonce.to_le_bytes()).await?;
+        Ok(Stream::keyed(inner, key, nonce, true))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Stream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(keystream)) = (&result, this.read.as_mut()) {
+            keystream.apply(&mut buf.filled_mut()[filled..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Stream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(keystream) = this.write.as_mut() else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+
+        keystream.peek_apply(buf, &mut this.scratch);
+        let result = Pin::new(&mut this.inner).poll_write(cx, &this.scratch);
+        if let Poll::Ready(Ok(written)) = result {
+            keystream.consume(written);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,78 @@
+//! Pacing of new upstream connections.
+//!
+//! After a game server restart every client reconnects at once. The pacer
+//! hands out upstream connect slots at `pacing.max_connects_per_sec`, holding
+//! the extra clients in a bounded queue instead of letting the stampede
+//! through to the server.
+
+use crate::config::PacingConfig;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+#[derive(Default)]
+pub struct Pacer {
+    next_slot: Mutex<Option<Instant>>,
+    waiting: AtomicUsize,
+}
+
+#[derive(Debug)]
+pub enum PacingError {
+    QueueFull,
+    WaitTooLong(Duration),
+}
+
+impl fmt::Display for PacingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacingError::QueueFull => write!(f, "upstream connection queue is full"),
+            PacingError::WaitTooLong(wait) => {
+                write!(f, "upstream connection slot is {}s away", wait.as_secs())
+            }
+        }
+    }
+}
+
+impl std::error::Error for PacingError {}
+
+impl Pacer {
+    /// Waits until this connection may open its upstream connection.
+    ///
+    /// Returns immediately when pacing is disabled.
+    pub async fn wait_turn(&self, config: &PacingConfig) -> Result<(), PacingError> {
+        if config.max_connects_per_sec == 0 {
+            return Ok(());
+        }
+
+        if self.waiting.fetch_add(1, Ordering::SeqCst) >= config.queue_size {
+            self.waiting.fetch_sub(1, Ordering::SeqCst);
+            return Err(PacingError::QueueFull);
+        }
+
+        let result = self.reserve_slot(config).await;
+        if let Ok(slot) = result {
+            tokio::time::sleep_until(slot).await;
+        }
+
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        result.map(|_| ())
+    }
+
+    async fn reserve_slot(&self, config: &PacingConfig) -> Result<Instant, PacingError> {
+        let spacing = Duration::from_secs(1) / config.max_connects_per_sec;
+        let now = Instant::now();
+
+        let mut next_slot = self.next_slot.lock().await;
+        let slot = next_slot.map_or(now, |next| next.max(now));
+
+        let wait = slot - now;
+        if wait > Duration::from_secs(config.max_wait_secs) {
+            return Err(PacingError::WaitTooLong(wait));
+        }
+
+        *next_slot = Some(slot + spacing);
+        Ok(slot)
+    }
+}
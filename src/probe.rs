@@ -0,0 +1,122 @@
+//! Background prober measuring the path to each upstream.
+//!
+//! Every `probe.interval_secs` the prober opens a TCP connection to each
+//! upstream and records how long the handshake took, keeping the last
+//! `probe.window` samples to derive an average RTT and a loss ratio.
+
+use crate::config::Config;
+use crate::{debug, info};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamHealth {
+    /// Average connect time over the successful samples in the window.
+    pub rtt: Option<Duration>,
+    /// Fraction of failed probes in the window, between 0 and 1.
+    pub loss: f64,
+}
+
+#[derive(Default)]
+struct Samples {
+    window: VecDeque<Option<Duration>>,
+}
+
+impl Samples {
+    fn record(&mut self, sample: Option<Duration>, window: usize) {
+        self.window.push_back(sample);
+        while self.window.len() > window.max(1) {
+            self.window.pop_front();
+        }
+    }
+
+    fn health(&self) -> UpstreamHealth {
+        let successful: Vec<Duration> = self.window.iter().flatten().copied().collect();
+        let rtt = if successful.is_empty() {
+            None
+        } else {
+            Some(successful.iter().sum::<Duration>() / successful.len() as u32)
+        };
+        let loss = if self.window.is_empty() {
+            0.0
+        } else {
+            (self.window.len() - successful.len()) as f64 / self.window.len() as f64
+        };
+        UpstreamHealth { rtt, loss }
+    }
+}
+
+/// Latest probe results, shared between the prober and its consumers.
+#[derive(Clone, Default)]
+pub struct ProbeResults {
+    upstreams: Arc<RwLock<HashMap<String, Samples>>>,
+}
+
+impl ProbeResults {
+    pub fn health(&self, upstream: &str) -> Option<UpstreamHealth> {
+        let upstreams = self.upstreams.read().unwrap();
+        upstreams.get(upstream).map(Samples::health)
+    }
+
+    fn record(&self, upstream: &str, sample: Option<Duration>, window: usize) -> (UpstreamHealth, bool) {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let samples = upstreams.entry(upstream.to_string()).or_default();
+        let was_reachable = samples.window.back().map(Option::is_some);
+        samples.record(sample, window);
+        let changed = was_reachable.is_some_and(|was| was != sample.is_some());
+        (samples.health(), changed)
+    }
+}
+
+/// Whether anything in `config` needs probe results.
+fn wanted(config: &Config) -> bool {
+    config.probe.enabled || config.advertisement.is_some()
+}
+
+pub async fn run(config: watch::Receiver<Arc<Config>>, results: ProbeResults) {
+    loop {
+        let current = Arc::clone(&config.borrow());
+        let interval = Duration::from_secs(current.probe.interval_secs.max(1));
+
+        if wanted(&current) {
+            probe_all(&current, &results).await;
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn probe_all(config: &Config, results: &ProbeResults) {
+    let probe_timeout = Duration::from_millis(config.probe.timeout_ms);
+    let upstreams = crate::routing::all_addresses(config);
+
+    let probes = upstreams.iter().map(|upstream| async move {
+        let started = Instant::now();
+        let sample = match timeout(probe_timeout, TcpStream::connect(upstream)).await {
+            Ok(Ok(_)) => Some(started.elapsed()),
+            _ => None,
+        };
+        (upstream, sample)
+    });
+
+    for (upstream, sample) in futures::future::join_all(probes).await {
+        let (health, changed) = results.record(upstream, sample, config.probe.window);
+        debug!(
+            "[probe] - {}: sample {:?}, average rtt {:?}, loss {:.0}%",
+            upstream,
+            sample,
+            health.rtt,
+            health.loss * 100.0
+        );
+        if changed {
+            match sample {
+                Some(_) => info!("Upstream {} is reachable again", upstream),
+                None => info!("Upstream {} stopped answering probes", upstream),
+            }
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::cli::Cli;
+use crate::config::Config;
+use crate::{info, logging, routing};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// Re-reads the configuration every time the process receives SIGHUP.
+///
+/// New connections pick up the reloaded settings; the ones already running
+/// keep the snapshot they started with, so nothing is dropped.
+pub async fn run(cli: Cli, config: watch::Sender<Arc<Config>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("Error: cannot install SIGHUP handler, reload disabled: {}", e);
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        reload(&cli, &config);
+    }
+}
+
+fn reload(cli: &Cli, config: &watch::Sender<Arc<Config>>) {
+    let mut new_config = match Config::from_cli(cli) {
+        Ok(new_config) => new_config,
+        Err(e) => {
+            eprintln!("Error: reload failed, keeping the current configuration: {}", e);
+            return;
+        }
+    };
+
+    let current = config.borrow().clone();
+
+    // Sockets are bound once at startup, so these can't change without a restart.
+    if listen_addresses(&new_config) != listen_addresses(&current) {
+        eprintln!(
+            "Warning: adding, removing or moving listeners requires a restart, keeping the current listeners"
+        );
+        new_config.listen = current.listen.clone();
+        new_config.target = current.target.clone();
+        new_config.listeners = current.listeners.clone();
+    }
+    if knock_addresses(&new_config) != knock_addresses(&current) {
+        eprintln!("Warning: changing knock ports requires a restart, keeping the current knocking settings");
+        new_config.knocking = current.knocking.clone();
+        for listener in &mut new_config.listeners {
+            listener.knocking = current.listener(&listener.listen).and_then(|current| current.knocking);
+        }
+    }
+    if new_config.advertisement.as_ref().map(|a| &a.listen) != current.advertisement.as_ref().map(|a| &a.listen) {
+        eprintln!("Warning: changing the advertisement listener requires a restart");
+        new_config.advertisement = current.advertisement.clone();
+    }
+
+    // Settings kept from the current configuration may not fit the new one,
+    // e.g. a kept listener forwarding to an upstream group that was renamed.
+    if let Err(e) = new_config.validate() {
+        eprintln!("Error: reload failed, keeping the current configuration: {}", e);
+        return;
+    }
+
+    logging::set_level(new_config.logging.level);
+    for route in new_config.listeners() {
+        if let Ok(destination) = routing::resolve(&new_config, &route) {
+            info!("[{}] Configuration reloaded, forwarding to {}", route.name(), destination);
+        }
+    }
+    config.send_replace(Arc::new(new_config));
+}
+
+fn listen_addresses(config: &Config) -> Vec<String> {
+    let mut addresses: Vec<String> = config.listeners().into_iter().map(|listener| listener.listen).collect();
+    addresses.sort();
+    addresses
+}
+
+fn knock_addresses(config: &Config) -> Vec<(String, Vec<String>)> {
+    let mut addresses: Vec<(String, Vec<String>)> = config
+        .listeners()
+        .into_iter()
+        .map(|listener| {
+            let knocks = listener.knock_addresses(config);
+            (listener.listen, knocks)
+        })
+        .collect();
+    addresses.sort();
+    addresses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn merged_config_that_fails_validation_is_not_applied() {
+        let dir = std::env::temp_dir().join(format!("proxi-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.toml");
+        let write = |listen: &str, group: &str| {
+            let contents = format!(
+                "[[listeners]]\nlisten = \"{}\"\nupstream = \"{}\"\n[upstreams.{}]\naddresses = [\"127.0.0.1:7173\"]\n",
+                listen, group, group
+            );
+            std::fs::write(&path, contents).unwrap();
+        };
+        let cli = Cli::parse_from(["proxi", "--config", path.to_str().unwrap()]);
+        let _env = crate::config::ENV_LOCK.lock().unwrap();
+
+        write("127.0.0.1:7001", "game");
+        let (config, current) = watch::channel(Arc::new(Config::from_cli(&cli).unwrap()));
+        // Moving the listener is refused, and the kept one would point at a removed group.
+        write("127.0.0.1:7002", "renamed");
+        reload(&cli, &config);
+
+        let current = current.borrow();
+        assert!(current.upstreams.contains_key("game"));
+        assert_eq!(current.listeners[0].listen, "127.0.0.1:7001");
+    }
+}
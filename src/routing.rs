@@ -0,0 +1,151 @@
+//! Resolution of a listener to the upstream servers its clients are sent to.
+//!
+//! A listener either names a single `target` address or an `upstream` group
+//! defined under `[upstreams.<name>]`. Groups are resolved against the current
+//! configuration for every new connection, so reloaded groups apply at once.
+//! Groups with a logical `name` get their addresses from `discovery`.
+
+use crate::config::{Config, ListenerConfig, ResolverKind};
+use crate::debug;
+use std::fmt;
+use tokio::io;
+use tokio::net::TcpStream;
+
+/// The upstream servers a listener forwards to.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    /// Group name, or the address itself for a plain `target`.
+    pub label: String,
+    pub addresses: Vec<String>,
+    /// Logical name the addresses are resolved from, empty until then.
+    pub lookup: Option<(ResolverKind, String)>,
+}
+
+#[derive(Debug)]
+pub enum RoutingError {
+    NoDestination(String),
+    AmbiguousDestination(String),
+    UnknownUpstream(String, String),
+    EmptyUpstream(String),
+    NamedUpstreamWithAddresses(String),
+    UnknownStaticName(String, String),
+}
+
+impl fmt::Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutingError::NoDestination(listener) => {
+                write!(f, "listener {} has neither a target nor an upstream", listener)
+            }
+            RoutingError::AmbiguousDestination(listener) => {
+                write!(f, "listener {} has both a target and an upstream", listener)
+            }
+            RoutingError::UnknownUpstream(listener, upstream) => {
+                write!(f, "listener {} uses undefined upstream {}", listener, upstream)
+            }
+            RoutingError::EmptyUpstream(upstream) => write!(f, "upstream {} has no addresses", upstream),
+            RoutingError::NamedUpstreamWithAddresses(upstream) => {
+                write!(f, "upstream {} has both a name and addresses", upstream)
+            }
+            RoutingError::UnknownStaticName(upstream, name) => {
+                write!(f, "upstream {} resolves {} which is not in [resolver.names]", upstream, name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+pub fn resolve(config: &Config, listener: &ListenerConfig) -> Result<Destination, RoutingError> {
+    match (&listener.target, &listener.upstream) {
+        (Some(target), None) => Ok(Destination {
+            label: target.clone(),
+            addresses: vec![target.clone()],
+            lookup: None,
+        }),
+        (None, Some(name)) => {
+            let upstream = config
+                .upstreams
+                .get(name)
+                .ok_or_else(|| RoutingError::UnknownUpstream(listener.name().to_string(), name.clone()))?;
+            if let Some(lookup) = &upstream.name {
+                if !upstream.addresses.is_empty() {
+                    return Err(RoutingError::NamedUpstreamWithAddresses(name.clone()));
+                }
+                if upstream.resolver == ResolverKind::Static && !config.resolver.names.contains_key(lookup) {
+                    return Err(RoutingError::UnknownStaticName(name.clone(), lookup.clone()));
+                }
+                return Ok(Destination {
+                    label: name.clone(),
+                    addresses: Vec::new(),
+                    lookup: Some((upstream.resolver, lookup.clone())),
+                });
+            }
+            if upstream.addresses.is_empty() {
+                return Err(RoutingError::EmptyUpstream(name.clone()));
+            }
+            Ok(Destination {
+                label: name.clone(),
+                addresses: upstream.addresses.clone(),
+                lookup: None,
+            })
+        }
+        (None, None) => Err(RoutingError::NoDestination(listener.name().to_string())),
+        (Some(_), Some(_)) => Err(RoutingError::AmbiguousDestination(listener.name().to_string())),
+    }
+}
+
+/// Every fixed upstream address any listener may forward to, without
+/// duplicates. Addresses behind logical names aren't known up front.
+pub fn all_addresses(config: &Config) -> Vec<String> {
+    let mut addresses: Vec<String> = config
+        .listeners()
+        .iter()
+        .filter_map(|listener| resolve(config, listener).ok())
+        .flat_map(|destination| destination.addresses)
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+impl Destination {
+    /// Connects to the first reachable address, starting at `start` and
+    /// wrapping around, so successive calls spread clients across the group.
+    /// SRV answers already come in the order to try them.
+    pub async fn connect(&self, start: usize) -> io::Result<TcpStream> {
+        let start = match self.lookup {
+            Some((ResolverKind::Srv, _)) => 0,
+            _ => start,
+        };
+        let mut last_error = None;
+        for offset in 0..self.addresses.len() {
+            let address = &self.addresses[(start + offset) % self.addresses.len()];
+            match TcpStream::connect(address).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("[routing] - {} unreachable in {}: {}", address, self.label, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "upstream has no addresses")))
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some((kind, name)), true) = (&self.lookup, self.addresses.is_empty()) {
+            let resolver = match kind {
+                ResolverKind::Dns => "dns",
+                ResolverKind::Static => "static",
+                ResolverKind::Srv => "srv",
+            };
+            write!(f, "{} ({} name {})", self.label, resolver, name)
+        } else if self.addresses.len() == 1 && self.addresses[0] == self.label {
+            write!(f, "{}", self.label)
+        } else {
+            write!(f, "{} ({})", self.label, self.addresses.join(", "))
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Per-packet sequence numbers of newer protocol versions.
+//!
+//! Newer clients send a u32 sequence number where older ones put the Adler-32
+//! checksum, one higher for every packet of the connection. The proxy checks
+//! that numbering and keeps its own count of the packets it injects, so
+//! injected packets take a number of their own and the packets forwarded
+//! after them are renumbered to keep the stream consistent.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub struct SequenceError {
+    pub expected: u32,
+    pub received: u32,
+    /// Number to send the packet on with, shifted like in-sequence packets.
+    pub sequence: u32,
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected sequence number {}, got {}", self.expected, self.received)
+    }
+}
+
+impl std::error::Error for SequenceError {}
+
+/// Sequence numbers of one direction of a connection.
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    /// Number the peer should send next, unknown until its first packet.
+    expected: Option<u32>,
+    /// Packets the proxy sent on its own so far; forwarded packets are
+    /// numbered this much higher than they came in.
+    injected: u32,
+    /// Number of the last packet sent on.
+    last_sent: Option<u32>,
+}
+
+impl Sequencer {
+    /// Takes note of a packet from the peer and returns the number to send it
+    /// on with. A packet out of sequence is reported instead, with the number
+    /// to send it on with shifted the same way, so the gap stays visible
+    /// upstream; checking goes on from its number.
+    pub fn forward(&mut self, received: u32) -> Result<u32, SequenceError> {
+        let expected = self.expected.replace(received.wrapping_add(1));
+        let sequence = received.wrapping_add(self.injected);
+        self.last_sent = Some(sequence);
+        match expected.filter(|expected| *expected != received) {
+            Some(expected) => Err(SequenceError {
+                expected,
+                received,
+                sequence,
+            }),
+            None => Ok(sequence),
+        }
+    }
+
+    /// Number for a packet the proxy sends on its own. Numbering starts at 0
+    /// when the proxy speaks first.
+    pub fn inject(&mut self) -> u32 {
+        let sequence = self.last_sent.map_or(0, |last| last.wrapping_add(1));
+        self.last_sent = Some(sequence);
+        self.injected = self.injected.wrapping_add(1);
+        sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Numbers `forward` sends `received` on with, out of sequence or not.
+    fn sent(sequencer: &mut Sequencer, received: u32) -> u32 {
+        sequencer.forward(received).unwrap_or_else(|e| e.sequence)
+    }
+
+    #[test]
+    fn in_sequence_packets_keep_their_numbers() {
+        let mut sequencer = Sequencer::default();
+        for received in 5..10 {
+            assert_eq!(sequencer.forward(received).unwrap(), received);
+        }
+    }
+
+    #[test]
+    fn injected_packets_shift_the_ones_after() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.forward(0).unwrap(), 0);
+        assert_eq!(sequencer.inject(), 1);
+        assert_eq!(sequencer.forward(1).unwrap(), 2);
+        assert_eq!(sequencer.inject(), 3);
+        assert_eq!(sequencer.forward(2).unwrap(), 4);
+    }
+
+    #[test]
+    fn injecting_first_starts_at_zero() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.inject(), 0);
+        assert_eq!(sequencer.forward(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn gap_is_reported_and_checking_goes_on() {
+        let mut sequencer = Sequencer::default();
+        sequencer.forward(0).unwrap();
+        let e = sequencer.forward(2).unwrap_err();
+        assert_eq!((e.expected, e.received, e.sequence), (1, 2, 2));
+        assert_eq!(sequencer.forward(3).unwrap(), 3);
+    }
+
+    #[test]
+    fn gap_after_an_inject_stays_visible() {
+        let mut sequencer = Sequencer::default();
+        let numbers = [
+            sent(&mut sequencer, 0),
+            sequencer.inject(),
+            sent(&mut sequencer, 1),
+            sent(&mut sequencer, 3),
+            sent(&mut sequencer, 4),
+        ];
+        assert_eq!(numbers, [0, 1, 2, 4, 5]);
+    }
+}
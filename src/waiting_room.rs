@@ -0,0 +1,125 @@
+//! Login queue held at the proxy.
+//!
+//! When a listener has `max_sessions` set, clients beyond the cap wait here
+//! and are admitted first come, first served as sessions end. While queued
+//! clients can also wait out an upstream that refuses connections.
+
+use crate::config::WaitingRoomConfig;
+use crate::debug;
+use crate::routing::Destination;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io;
+use tokio::net::TcpStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{timeout, Instant};
+
+pub struct WaitingRoom {
+    // tokio's semaphore hands out permits in request order, which gives FIFO admission.
+    sessions: Arc<Semaphore>,
+    capacity: Mutex<usize>,
+    queued: AtomicUsize,
+}
+
+/// Held for the lifetime of an admitted session; dropping it frees the slot.
+pub struct Admission {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+pub enum WaitingRoomError {
+    QueueFull,
+    TimedOut,
+}
+
+impl fmt::Display for WaitingRoomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WaitingRoomError::QueueFull => write!(f, "waiting room is full"),
+            WaitingRoomError::TimedOut => write!(f, "waited too long in the waiting room"),
+        }
+    }
+}
+
+impl std::error::Error for WaitingRoomError {}
+
+impl Default for WaitingRoom {
+    fn default() -> Self {
+        WaitingRoom {
+            sessions: Arc::new(Semaphore::new(0)),
+            capacity: Mutex::new(0),
+            queued: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl WaitingRoom {
+    /// Waits for a session slot. Returns at once when no cap is configured.
+    pub async fn enter(&self, config: &WaitingRoomConfig) -> Result<Admission, WaitingRoomError> {
+        if config.max_sessions == 0 {
+            return Ok(Admission { _permit: None });
+        }
+        self.resize(config.max_sessions);
+
+        if let Ok(permit) = Arc::clone(&self.sessions).try_acquire_owned() {
+            return Ok(Admission { _permit: Some(permit) });
+        }
+
+        let position = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        if position > config.queue_size {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(WaitingRoomError::QueueFull);
+        }
+        debug!("[waiting_room] - Session cap reached, queued at position {}", position);
+
+        let max_wait = Duration::from_secs(config.max_wait_secs);
+        let result = timeout(max_wait, Arc::clone(&self.sessions).acquire_owned()).await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+
+        match result {
+            Ok(Ok(permit)) => Ok(Admission { _permit: Some(permit) }),
+            _ => Err(WaitingRoomError::TimedOut),
+        }
+    }
+
+    /// Follows `max_sessions` changes from reloads. Slots in use can't be taken
+    /// back, so shrinking completes as those sessions end.
+    fn resize(&self, max_sessions: usize) {
+        let mut capacity = self.capacity.lock().unwrap();
+        if max_sessions > *capacity {
+            self.sessions.add_permits(max_sessions - *capacity);
+            *capacity = max_sessions;
+        } else if max_sessions < *capacity {
+            *capacity -= self.sessions.forget_permits(*capacity - max_sessions);
+        }
+    }
+}
+
+/// Connects to `destination`, keeping the client waiting and retrying every
+/// `retry_secs` while the upstream refuses connections.
+pub async fn connect_upstream(
+    destination: &Destination,
+    start: usize,
+    config: &WaitingRoomConfig,
+) -> io::Result<TcpStream> {
+    let retry = Duration::from_secs(config.retry_secs);
+    let deadline = Instant::now() + Duration::from_secs(config.max_wait_secs);
+
+    loop {
+        match destination.connect(start).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if !retry.is_zero() && Instant::now() + retry < deadline => {
+                debug!(
+                    "[waiting_room] - {} refused the connection ({}), retrying in {}s",
+                    destination.label,
+                    e,
+                    retry.as_secs()
+                );
+                tokio::time::sleep(retry).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}